@@ -10,10 +10,47 @@ macro_rules! impl_debug_display {
     }
 }
 
+/// I/O errors.
+///
+/// Apart from `Custom`, the variants mirror the error codes returned by
+/// Ice Core (`lssa::error::ErrorCode`).
 #[derive(Debug)]
 pub enum Io {
     Generic,
-    Custom(String)
+    Custom(String),
+    Eof,
+    Shutdown,
+    PermissionDenied,
+    OngoingIo,
+    InvalidInput,
+    BindFail,
+    NotFound,
+    TimedOut,
+    ConnectionReset,
+    ConnectionRefused,
+    BrokenPipe,
+    TooLarge
+}
+
+impl Io {
+    /// Converts a negative code returned by Ice Core into an `Io` error.
+    pub fn from_code(code: i32) -> Io {
+        match -code {
+            2 => Io::Eof,
+            3 => Io::Shutdown,
+            4 => Io::PermissionDenied,
+            5 => Io::OngoingIo,
+            6 => Io::InvalidInput,
+            7 => Io::BindFail,
+            8 => Io::NotFound,
+            9 => Io::TimedOut,
+            10 => Io::ConnectionReset,
+            11 => Io::ConnectionRefused,
+            12 => Io::BrokenPipe,
+            13 => Io::TooLarge,
+            _ => Io::Generic
+        }
+    }
 }
 
 pub type IoResult<T> = Result<T, Io>;
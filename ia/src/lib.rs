@@ -7,7 +7,7 @@
 //!
 //! - Asynchronous TCP server and client
 //! - File I/O
-//! - Timer (not working for now due to an Ice bug)
+//! - Timers
 //!
 //! The asynchronous APIs are based on `futures`, while low-level callback-based APIs
 //! are also provided.
@@ -10,6 +10,9 @@ use error::IoResult;
 pub struct TcpListener {
     notify: Rc<UnsafeCell<VecDeque<::raw::TcpStream>>>,
     listening: bool,
+    listener_id: Option<i32>,
+    max_conns: usize,
+    dual_stack: bool,
     addr: String
 }
 
@@ -26,13 +29,17 @@ impl Stream for TcpListener {
 
             self.listening = true;
 
-            ::raw::listen_tcp(&self.addr, move |s| {
+            let flags = if self.dual_stack { ::raw::LISTEN_DUAL_STACK } else { 0 };
+            let listener_id = ::raw::listen_tcp(&self.addr, self.max_conns, flags, move |s| {
                 let notify = unsafe {
                     &mut *notify.get()
                 };
                 notify.push_back(s);
                 ::executor::run_once_next_tick(&task);
             });
+            if listener_id >= 0 {
+                self.listener_id = Some(listener_id);
+            }
         }
 
         let notify = unsafe {
@@ -48,18 +55,61 @@ impl Stream for TcpListener {
 }
 
 impl TcpListener {
-    /// Listens on the specified address (in the format `ip:port`).
+    /// Listens on the specified address (in the format `ip:port`, or
+    /// `[ipv6]:port`).
     ///
     /// The application must have `TcpListenAny` or `TcpListen(addr)` permissions.
     pub fn new(addr: &str) -> TcpListener {
         TcpListener {
             addr: addr.to_string(),
             listening: false,
+            listener_id: None,
+            max_conns: 0,
+            dual_stack: false,
             notify: Rc::new(
                 UnsafeCell::new(VecDeque::new())
             )
         }
     }
+
+    /// Limits the number of accepted connections that are alive at the same time.
+    ///
+    /// Incoming connections over the limit are closed by Ice without being
+    /// yielded. Must be called before the listener is first polled.
+    pub fn max_connections(mut self, n: usize) -> TcpListener {
+        self.max_conns = n;
+        self
+    }
+
+    /// Also accepts IPv4 connections when listening on an IPv6 address such
+    /// as `[::]:80`. Must be called before the listener is first polled.
+    pub fn dual_stack(mut self, enabled: bool) -> TcpListener {
+        self.dual_stack = enabled;
+        self
+    }
+
+    /// Returns the address the listener is bound to. Useful to learn the
+    /// chosen port when listening on port 0.
+    ///
+    /// Fails with `NotFound` until the listener has been polled once.
+    pub fn local_addr(&self) -> IoResult<String> {
+        match self.listener_id {
+            Some(id) => ::raw::listener_addr_tcp(id),
+            None => Err(::error::Io::NotFound)
+        }
+    }
+}
+
+impl Drop for TcpListener {
+    /// Stops accepting new connections. Connections that were already
+    /// accepted are not affected.
+    fn drop(&mut self) {
+        if let Some(id) = self.listener_id.take() {
+            ::raw::stop_listen_tcp(id).unwrap_or_else(|e| {
+                eprintln!("Unable to stop listener: {:?}", e);
+            });
+        }
+    }
 }
 
 /// A TCP connection that can be read from or written to.
@@ -80,6 +130,30 @@ impl TcpConnection {
         }
     }
 
+    /// Returns the address of the remote peer (in the format `ip:port`).
+    pub fn peer_addr(&self) -> IoResult<String> {
+        self.raw.peer_addr()
+    }
+
+    /// Enables or disables Nagle's algorithm (`TCP_NODELAY`) on the connection.
+    pub fn set_nodelay(&self, enabled: bool) -> IoResult<()> {
+        self.raw.set_nodelay(enabled)
+    }
+
+    /// Shuts down the read half, the write half or both halves of the
+    /// connection. Closing only the write half signals end-of-stream to
+    /// the peer while reads continue to work.
+    pub fn shutdown(&self, how: ::std::net::Shutdown) -> IoResult<()> {
+        self.raw.shutdown(how)
+    }
+
+    /// Sets a timeout for subsequent reads, in milliseconds. `0` disables it.
+    ///
+    /// A read that times out fails without closing the connection.
+    pub fn set_read_timeout(&self, millis: i32) -> IoResult<()> {
+        self.raw.set_read_timeout(millis)
+    }
+
     /// Writes to the connection.
     ///
     /// The `Future` returned by this resolves after all bytes are written successfully
@@ -181,21 +255,17 @@ impl Future for ReadFuture {
         let max_len = self.max_len;
 
         self.stream.read(self.max_len, move |buf| {
-            *status.borrow_mut() = Some(match buf {
-                Ok(buf) => {
-                    let mut buffer: Vec<u8> = Vec::with_capacity(max_len);
-                    unsafe {
-                        buffer.set_len(max_len);
-                    }
-                    let real_len = buf.take(&mut buffer);
-                    assert!(real_len <= max_len);
-                    unsafe {
-                        buffer.set_len(real_len);
-                    }
-                    Ok(buffer)
-                },
-                Err(e) => Err(e)
-            });
+            *status.borrow_mut() = Some(buf.and_then(|buf| {
+                let mut buffer: Vec<u8> = Vec::with_capacity(max_len);
+                unsafe {
+                    buffer.set_len(max_len);
+                }
+                let real_len = buf.take(&mut buffer)?;
+                unsafe {
+                    buffer.set_len(real_len);
+                }
+                Ok(buffer)
+            }));
             ::executor::run_once_next_tick(&task);
         });
 
@@ -234,7 +304,7 @@ impl Future for WriteFuture {
         let status = self.status.clone();
         let task = ::executor::current_task();
 
-        self.stream.write(&self.data, move |result| {
+        self.stream.write(&self.data, move |result, _| {
             *status.borrow_mut() = Some(result);
             ::executor::run_once_next_tick(&task);
         });
@@ -31,7 +31,15 @@ extern "C" {
         addr_base: *const u8,
         addr_len: usize,
         cb: extern "C" fn (user_data: i32, stream_tid: i32) -> i32,
-        user_data: i32
+        user_data: i32,
+        max_conns: usize,
+        flags: i32
+    ) -> i32;
+    fn __ice_tcp_stop_listen(listener_id: i32) -> i32;
+    fn __ice_tcp_listener_addr(
+        listener_id: i32,
+        out_base: *mut u8,
+        out_len: usize
     ) -> i32;
     fn __ice_tcp_release_buffer(
         buffer_id: i32
@@ -40,7 +48,8 @@ extern "C" {
         buffer_id: i32,
         output: *mut u8,
         output_len: usize
-    ) -> usize;
+    ) -> i32;
+    fn __ice_tcp_buffer_len(buffer_id: i32) -> i32;
     fn __ice_tcp_read(
         stream_tid: i32,
         read_len: usize,
@@ -51,10 +60,113 @@ extern "C" {
         stream_tid: i32,
         data_base: *const u8,
         data_len: usize,
-        cb: extern "C" fn (user_data: i32, len: i32) -> i32,
+        cb: extern "C" fn (user_data: i32, len: i32, n_bytes_written: i32) -> i32,
         user_data: i32
     );
     fn __ice_tcp_destroy(stream_tid: i32);
+    fn __ice_tcp_shutdown(stream_tid: i32, how: i32) -> i32;
+    fn __ice_tcp_peer_addr(
+        stream_tid: i32,
+        output: *mut u8,
+        output_len: usize
+    ) -> i32;
+    fn __ice_tcp_set_nodelay(stream_tid: i32, enabled: i32) -> i32;
+    fn __ice_tcp_set_read_timeout(stream_tid: i32, millis: i32) -> i32;
+    fn __ice_tcp_set_idle_timeout(
+        millis: i32,
+        cb: extern "C" fn (user_data: i32, stream_tid: i32) -> i32,
+        user_data: i32
+    ) -> i32;
+    fn __ice_uds_listen(
+        path_base: *const u8,
+        path_len: usize,
+        cb: extern "C" fn (user_data: i32, stream_id: i32) -> i32,
+        user_data: i32
+    ) -> i32;
+    fn __ice_uds_stop_listen(listener_id: i32) -> i32;
+    fn __ice_uds_connect(
+        path_base: *const u8,
+        path_len: usize,
+        cb: extern "C" fn (user_data: i32, stream_id: i32) -> i32,
+        user_data: i32
+    );
+    fn __ice_uds_read(
+        stream_id: i32,
+        read_len: usize,
+        cb: extern "C" fn (user_data: i32, len: i32) -> i32,
+        user_data: i32
+    );
+    fn __ice_uds_write(
+        stream_id: i32,
+        data_base: *const u8,
+        data_len: usize,
+        cb: extern "C" fn (user_data: i32, len: i32, n_bytes_written: i32) -> i32,
+        user_data: i32
+    );
+    fn __ice_uds_destroy(stream_id: i32);
+    fn __ice_uds_release_buffer(buffer_id: i32);
+    fn __ice_uds_take_buffer(
+        buffer_id: i32,
+        output: *mut u8,
+        output_len: usize
+    ) -> i32;
+    fn __ice_uds_buffer_len(buffer_id: i32) -> i32;
+    fn __ice_udp_bind(
+        addr_base: *const u8,
+        addr_len: usize
+    ) -> i32;
+    fn __ice_udp_send_to(
+        socket_id: i32,
+        data_base: *const u8,
+        data_len: usize,
+        addr_base: *const u8,
+        addr_len: usize,
+        cb: extern "C" fn (user_data: i32, len: i32) -> i32,
+        user_data: i32
+    );
+    fn __ice_udp_recv_from(
+        socket_id: i32,
+        recv_len: usize,
+        cb: extern "C" fn (user_data: i32, data_buffer_id: i32, addr_buffer_id: i32) -> i32,
+        user_data: i32
+    );
+    fn __ice_udp_release_buffer(
+        buffer_id: i32
+    );
+    fn __ice_udp_take_buffer(
+        buffer_id: i32,
+        output: *mut u8,
+        output_len: usize
+    ) -> i32;
+    fn __ice_udp_buffer_len(buffer_id: i32) -> i32;
+    fn __ice_udp_destroy(socket_id: i32);
+    fn __ice_httpc_request(
+        method_base: *const u8,
+        method_len: usize,
+        url_base: *const u8,
+        url_len: usize,
+        headers_base: *const u8,
+        headers_len: usize,
+        body_base: *const u8,
+        body_len: usize,
+        cb: extern "C" fn (user_data: i32, response_id: i32) -> i32,
+        user_data: i32
+    );
+    fn __ice_httpc_status(response_id: i32) -> i32;
+    fn __ice_httpc_headers(response_id: i32) -> i32;
+    fn __ice_httpc_read_body(
+        response_id: i32,
+        cb: extern "C" fn (user_data: i32, buffer_id: i32) -> i32,
+        user_data: i32
+    );
+    fn __ice_httpc_destroy(response_id: i32) -> i32;
+    fn __ice_httpc_buffer_len(buffer_id: i32) -> i32;
+    fn __ice_httpc_release_buffer(buffer_id: i32);
+    fn __ice_httpc_take_buffer(
+        buffer_id: i32,
+        output: *mut u8,
+        output_len: usize
+    ) -> i32;
     fn __ice_file_open(
         path_base: *const u8,
         path_len: usize,
@@ -78,10 +190,185 @@ extern "C" {
         from: i32,
         offset: i64
     ) -> i64;
+    fn __ice_file_read_file(
+        path_base: *const u8,
+        path_len: usize,
+        cb: extern "C" fn (user_data: i32, buffer_id: i32) -> i32,
+        user_data: i32
+    );
+    fn __ice_file_write_file(
+        path_base: *const u8,
+        path_len: usize,
+        data_base: *const u8,
+        data_len: usize,
+        cb: extern "C" fn (user_data: i32, n_bytes_written: i32) -> i32,
+        user_data: i32
+    );
+    fn __ice_file_buffer_len(buffer_id: i32) -> i32;
+    fn __ice_file_release_buffer(buffer_id: i32);
+    fn __ice_file_take_buffer(
+        buffer_id: i32,
+        output: *mut u8,
+        output_len: usize
+    ) -> i32;
     fn __ice_timer_now_millis() -> i64;
+    fn __ice_timer_monotonic_millis() -> i64;
     fn __ice_timer_set_immediate(cb: extern "C" fn (user_data: i32) -> i32, user_data: i32);
+    fn __ice_timer_set_timeout(
+        millis: i64,
+        cb: extern "C" fn (user_data: i32, ret: i32) -> i32,
+        user_data: i32
+    ) -> i32;
+    fn __ice_timer_clear_timeout(id: i32) -> i32;
     fn __ice_logging_info(base: *const u8, len: usize);
     fn __ice_logging_warning(base: *const u8, len: usize);
+    fn __ice_logging_log(level: i32, base: *const u8, len: usize) -> i32;
+    fn __ice_random_fill_random(base: *mut u8, len: usize) -> i32;
+    fn __ice_env_get_env(name_base: *const u8, name_len: usize) -> i32;
+    fn __ice_env_buffer_len(buffer_id: i32) -> i32;
+    fn __ice_env_release_buffer(buffer_id: i32);
+    fn __ice_env_take_buffer(
+        buffer_id: i32,
+        output: *mut u8,
+        output_len: usize
+    ) -> i32;
+    fn __ice_msg_send(
+        target_base: *const u8,
+        target_len: usize,
+        payload_base: *const u8,
+        payload_len: usize,
+        cb: extern "C" fn (user_data: i32, ret: i32) -> i32,
+        user_data: i32
+    );
+    fn __ice_msg_set_receiver(
+        cb: extern "C" fn (user_data: i32, payload_id: i32, sender_id: i32) -> i32,
+        user_data: i32
+    );
+    fn __ice_msg_buffer_len(buffer_id: i32) -> i32;
+    fn __ice_msg_release_buffer(buffer_id: i32);
+    fn __ice_msg_take_buffer(
+        buffer_id: i32,
+        output: *mut u8,
+        output_len: usize
+    ) -> i32;
+}
+
+/// Reads a host environment variable.
+///
+/// Returns `None` if the variable is unset or not in the application's
+/// `allowed_env` list.
+pub fn get_env(name: &str) -> Option<String> {
+    let buffer_id = unsafe {
+        __ice_env_get_env(name.as_ptr(), name.len())
+    };
+    if buffer_id < 0 {
+        return None;
+    }
+
+    let len = unsafe { __ice_env_buffer_len(buffer_id) };
+    assert!(len >= 0);
+
+    let mut buffer: Vec<u8> = vec! [ 0; len as usize ];
+    let real_len = unsafe { __ice_env_take_buffer(
+        buffer_id,
+        buffer.as_mut_ptr(),
+        len as usize
+    ) };
+    assert!(real_len >= 0);
+    buffer.truncate(real_len as usize);
+    Some(String::from_utf8_lossy(&buffer).into_owned())
+}
+
+/// Sends `payload` to the application named `target`. Requires the
+/// `MessageApp(target)` permission.
+///
+/// `cb` is called once the target's receiver has been invoked, with
+/// `NotFound` if the target isn't running or has no receiver, or with
+/// `Generic` if the target's receiver traps.
+pub fn send_message<F: FnOnce(IoResult<()>) + 'static>(target: &str, payload: &[u8], cb: F) {
+    let cb: Box<FnBox(i32) -> i32> = Box::new(move |ret| {
+        cb(if ret >= 0 {
+            Ok(())
+        } else {
+            Err(error::Io::from_code(ret))
+        });
+        0
+    });
+    let (cb, raw_ctx) = cb.wrap_callback();
+
+    unsafe {
+        __ice_msg_send(
+            target.as_ptr(),
+            target.len(),
+            payload.as_ptr(),
+            payload.len(),
+            cb,
+            raw_ctx
+        )
+    }
+}
+
+/// Registers `cb` to receive messages from other applications, as
+/// `(payload, sender_name)`. Replaces any previous receiver; the previous
+/// callback is leaked.
+pub fn set_message_receiver<F: Fn(Vec<u8>, String) + 'static>(cb: F) {
+    let cb: Box<Fn(i32, i32) -> i32> = Box::new(move |payload_id, sender_id| {
+        let payload = take_msg_buffer(payload_id);
+        let sender = take_msg_buffer(sender_id);
+        cb(payload, String::from_utf8_lossy(&sender).into_owned());
+        0
+    });
+    let (cb, raw_ctx) = cb.wrap_callback();
+
+    unsafe {
+        __ice_msg_set_receiver(cb, raw_ctx);
+    }
+}
+
+fn take_msg_buffer(buffer_id: i32) -> Vec<u8> {
+    let len = unsafe { __ice_msg_buffer_len(buffer_id) };
+    assert!(len >= 0);
+
+    let mut buffer: Vec<u8> = vec! [ 0; len as usize ];
+    let real_len = unsafe { __ice_msg_take_buffer(
+        buffer_id,
+        buffer.as_mut_ptr(),
+        len as usize
+    ) };
+    assert!(real_len >= 0);
+    buffer.truncate(real_len as usize);
+    buffer
+}
+
+/// Fills `out` with cryptographically secure random bytes.
+pub fn fill_random(out: &mut [u8]) -> IoResult<()> {
+    let ret = unsafe {
+        __ice_random_fill_random(out.as_mut_ptr(), out.len())
+    };
+    if ret >= 0 {
+        Ok(())
+    } else {
+        Err(error::Io::from_code(ret))
+    }
+}
+
+/// Log levels accepted by `write_log`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum LogLevel {
+    Error = 0,
+    Warning = 1,
+    Info = 2,
+    Debug = 3
+}
+
+pub fn write_log(level: LogLevel, s: &str) {
+    unsafe {
+        __ice_logging_log(
+            level as i32,
+            s.as_ptr(),
+            s.len()
+        );
+    }
 }
 
 pub fn write_info(s: &str) {
@@ -208,10 +495,49 @@ impl_wrap_callback!(a: i32, b: i32, c: i32, d: i32, );
 impl_wrap_callback!(a: i32, b: i32, c: i32, d: i32, e: i32, );
 impl_wrap_callback!(a: i32, b: i32, c: i32, d: i32, e: i32, f: i32, );
 
-/*
-pub fn set_timeout<T: FnOnce()>(ms: i64, cb: T) {
-    unimplemented!()
-}*/
+/// A pending timeout created by `set_timeout`.
+pub struct Timeout {
+    id: i32,
+    raw_ctx: i32
+}
+
+/// Calls `cb` once after `ms` milliseconds, or with an error if the host
+/// timer failed.
+pub fn set_timeout<T: FnOnce(IoResult<()>) + 'static>(ms: i64, cb: T) -> Timeout {
+    let cb: Box<FnBox(i32) -> i32> = Box::new(|ret| {
+        cb(if ret >= 0 {
+            Ok(())
+        } else {
+            Err(error::Io::from_code(ret))
+        });
+        0
+    });
+    let (cb, raw_ctx) = cb.wrap_callback();
+    let id = unsafe {
+        __ice_timer_set_timeout(ms, cb, raw_ctx)
+    };
+    Timeout {
+        id: id,
+        raw_ctx: raw_ctx
+    }
+}
+
+/// Cancels a timeout that has not fired yet. Fails with `NotFound` once it
+/// has fired.
+pub fn clear_timeout(t: Timeout) -> IoResult<()> {
+    let ret = unsafe {
+        __ice_timer_clear_timeout(t.id)
+    };
+    if ret < 0 {
+        return Err(error::Io::from_code(ret));
+    }
+
+    // The callback will never be invoked now, so free it here.
+    unsafe {
+        Box::from_raw(t.raw_ctx as *mut Box<FnBox(i32) -> i32>);
+    }
+    Ok(())
+}
 
 pub fn schedule<T: FnOnce() + 'static>(cb: T) {
     let cb: Box<FnBox() -> i32> = Box::new(|| { cb(); 0 });
@@ -221,9 +547,24 @@ pub fn schedule<T: FnOnce() + 'static>(cb: T) {
     }
 }
 
-pub fn time() -> i64 {
-    unsafe {
+/// Returns the wall clock time in milliseconds since the Unix epoch.
+///
+/// The application must have the `ReadClock` permission.
+pub fn time() -> IoResult<i64> {
+    let ret = unsafe {
         __ice_timer_now_millis()
+    };
+    if ret >= 0 {
+        Ok(ret)
+    } else {
+        Err(error::Io::from_code(ret as i32))
+    }
+}
+
+/// Returns milliseconds elapsed since the application was loaded.
+pub fn monotonic_time() -> i64 {
+    unsafe {
+        __ice_timer_monotonic_millis()
     }
 }
 
@@ -267,31 +608,47 @@ impl Drop for TcpBuffer {
 }
 
 impl TcpBuffer {
-    pub fn take(self, out: &mut [u8]) -> usize {
+    /// Length of the buffer, i.e. the space `take` needs.
+    pub fn len(&self) -> usize {
+        let len = unsafe { __ice_tcp_buffer_len(self.handle) };
+        assert!(len >= 0);
+        len as usize
+    }
+
+    /// Copies the buffer into `out` and returns its length.
+    ///
+    /// Fails with `InvalidInput` if `out` is too small; the buffer is then
+    /// released. Use `len` to size `out`.
+    pub fn take(self, out: &mut [u8]) -> IoResult<usize> {
         let out_len = out.len();
-        let real_len = unsafe { __ice_tcp_take_buffer(
+        let ret = unsafe { __ice_tcp_take_buffer(
             self.handle,
-            &mut out[0],
+            out.as_mut_ptr(),
             out_len
         ) };
+        if ret < 0 {
+            return Err(error::Io::from_code(ret));
+        }
         ::std::mem::forget(self);
-        real_len
+        Ok(ret as usize)
     }
 }
 
 impl TcpStreamImpl {
-    pub fn write<F: FnOnce(IoResult<i32>) + 'static>(&self, data: &[u8], cb: F) {
+    /// Writes all of `data`. The second argument to `cb` is the number of
+    /// bytes written, which on failure tells where to resume.
+    pub fn write<F: FnOnce(IoResult<i32>, usize) + 'static>(&self, data: &[u8], cb: F) {
         if data.len() == 0 {
-            cb(Err(error::Io::Generic));
+            cb(Err(error::Io::Generic), 0);
             return;
         }
 
-        let cb: Box<FnBox(i32) -> i32> = Box::new(|a| {
+        let cb: Box<FnBox(i32, i32) -> i32> = Box::new(|a, written| {
             cb(if a >= 0 {
                 Ok(a)
             } else {
-                Err(error::Io::Generic)
-            });
+                Err(error::Io::from_code(a))
+            }, written as usize);
             0
         });
         let (cb, raw_ctx) = cb.wrap_callback();
@@ -307,12 +664,70 @@ impl TcpStreamImpl {
         }
     }
 
+    pub fn peer_addr(&self) -> IoResult<String> {
+        let mut out: [u8; 64] = [0; 64];
+        let out_len = out.len();
+
+        let ret = unsafe { __ice_tcp_peer_addr(
+            self.handle,
+            &mut out[0],
+            out_len
+        ) };
+        if ret >= 0 {
+            Ok(String::from_utf8_lossy(&out[0..ret as usize]).into_owned())
+        } else {
+            Err(error::Io::from_code(ret))
+        }
+    }
+
+    pub fn set_nodelay(&self, enabled: bool) -> IoResult<()> {
+        let ret = unsafe {
+            __ice_tcp_set_nodelay(self.handle, if enabled { 1 } else { 0 })
+        };
+        if ret >= 0 {
+            Ok(())
+        } else {
+            Err(error::Io::from_code(ret))
+        }
+    }
+
+    /// Shuts down the read half, the write half or both halves of the stream.
+    pub fn shutdown(&self, how: ::std::net::Shutdown) -> IoResult<()> {
+        use std::net::Shutdown;
+
+        let how = match how {
+            Shutdown::Read => 0,
+            Shutdown::Write => 1,
+            Shutdown::Both => 2
+        };
+        let ret = unsafe {
+            __ice_tcp_shutdown(self.handle, how)
+        };
+        if ret >= 0 {
+            Ok(())
+        } else {
+            Err(error::Io::from_code(ret))
+        }
+    }
+
+    /// Sets the timeout in milliseconds for subsequent reads. `0` disables it.
+    pub fn set_read_timeout(&self, millis: i32) -> IoResult<()> {
+        let ret = unsafe {
+            __ice_tcp_set_read_timeout(self.handle, millis)
+        };
+        if ret >= 0 {
+            Ok(())
+        } else {
+            Err(error::Io::from_code(ret))
+        }
+    }
+
     pub fn read<F: FnOnce(IoResult<TcpBuffer>) + 'static>(&self, len: usize, cb: F) {
         let cb: Box<FnBox(i32) -> i32> = Box::new(|a| {
             cb(if a >= 0 {
                 Ok(TcpBuffer { handle: a })
             } else {
-                Err(error::Io::Generic)
+                Err(error::Io::from_code(a))
             });
             0
         });
@@ -329,8 +744,15 @@ impl TcpStreamImpl {
     }
 }
 
+/// `listen_tcp` flag: accept IPv4 connections on an IPv6 listener.
+pub const LISTEN_DUAL_STACK: i32 = 1;
+
+/// Listens on `addr`. `max_conns` limits the number of accepted streams
+/// that haven't been dropped yet; `0` means no limit.
 pub fn listen_tcp<T: Fn(TcpStream) + 'static>(
     addr: &str,
+    max_conns: usize,
+    flags: i32,
     cb: T
 ) -> i32 {
     let cb: Box<Fn(i32) -> i32> = Box::new(move |stream_tid| {
@@ -351,11 +773,64 @@ pub fn listen_tcp<T: Fn(TcpStream) + 'static>(
             &addr[0],
             addr.len(),
             cb,
-            raw_ctx
+            raw_ctx,
+            max_conns,
+            flags
         )
     }
 }
 
+pub fn stop_listen_tcp(listener_id: i32) -> IoResult<()> {
+    let ret = unsafe {
+        __ice_tcp_stop_listen(listener_id)
+    };
+    if ret >= 0 {
+        Ok(())
+    } else {
+        Err(error::Io::from_code(ret))
+    }
+}
+
+/// Shuts down accepted streams that have been idle for `millis`
+/// milliseconds and calls `cb` with the raw id of each. Pending reads on
+/// such a stream see EOF. `0` disables the timeout.
+///
+/// Each call registers a new callback; the previous one is leaked.
+pub fn set_tcp_idle_timeout<F: Fn(i32) + 'static>(millis: i32, cb: F) -> IoResult<()> {
+    let cb: Box<Fn(i32) -> i32> = Box::new(move |stream_tid| {
+        cb(stream_tid);
+        0
+    });
+    let (cb, raw_ctx) = cb.wrap_callback();
+
+    let ret = unsafe {
+        __ice_tcp_set_idle_timeout(millis, cb, raw_ctx)
+    };
+    if ret >= 0 {
+        Ok(())
+    } else {
+        Err(error::Io::from_code(ret))
+    }
+}
+
+/// Returns the address the listener is bound to, including the port the
+/// OS picked if it was bound to port 0.
+pub fn listener_addr_tcp(listener_id: i32) -> IoResult<String> {
+    let mut out: [u8; 64] = [0; 64];
+    let out_len = out.len();
+
+    let ret = unsafe { __ice_tcp_listener_addr(
+        listener_id,
+        &mut out[0],
+        out_len
+    ) };
+    if ret >= 0 {
+        Ok(String::from_utf8_lossy(&out[0..ret as usize]).into_owned())
+    } else {
+        Err(error::Io::from_code(ret))
+    }
+}
+
 pub fn connect_tcp<F: FnOnce(IoResult<TcpStream>) + 'static>(
     addr: &str,
     cb: F
@@ -368,7 +843,7 @@ pub fn connect_tcp<F: FnOnce(IoResult<TcpStream>) + 'static>(
                 })
             })
         } else {
-            Err(error::Io::Generic)
+            Err(error::Io::from_code(stream_tid))
         });
 
         0
@@ -386,6 +861,389 @@ pub fn connect_tcp<F: FnOnce(IoResult<TcpStream>) + 'static>(
     }
 }
 
+/// A Unix domain socket stream. Requires the `UnixSocket(prefix)`
+/// permission for the socket path.
+#[derive(Clone)]
+pub struct UnixStream {
+    inner: Rc<UnixStreamImpl>
+}
+
+impl Deref for UnixStream {
+    type Target = UnixStreamImpl;
+    fn deref(&self) -> &Self::Target {
+        &*self.inner
+    }
+}
+
+pub struct UnixStreamImpl {
+    handle: i32
+}
+
+impl Drop for UnixStreamImpl {
+    fn drop(&mut self) {
+        unsafe {
+            __ice_uds_destroy(self.handle);
+        }
+    }
+}
+
+pub struct UnixBuffer {
+    handle: i32
+}
+
+impl Drop for UnixBuffer {
+    fn drop(&mut self) {
+        unsafe {
+            __ice_uds_release_buffer(self.handle);
+        }
+    }
+}
+
+impl UnixBuffer {
+    /// Length of the buffer, i.e. the space `take` needs.
+    pub fn len(&self) -> usize {
+        let len = unsafe { __ice_uds_buffer_len(self.handle) };
+        assert!(len >= 0);
+        len as usize
+    }
+
+    /// Copies the buffer into `out` and returns its length.
+    pub fn take(self, out: &mut [u8]) -> IoResult<usize> {
+        let out_len = out.len();
+        let ret = unsafe { __ice_uds_take_buffer(
+            self.handle,
+            out.as_mut_ptr(),
+            out_len
+        ) };
+        if ret < 0 {
+            return Err(error::Io::from_code(ret));
+        }
+        ::std::mem::forget(self);
+        Ok(ret as usize)
+    }
+}
+
+impl UnixStreamImpl {
+    /// Writes all of `data`. The second argument to `cb` is the number of
+    /// bytes written.
+    pub fn write<F: FnOnce(IoResult<i32>, usize) + 'static>(&self, data: &[u8], cb: F) {
+        if data.len() == 0 {
+            cb(Err(error::Io::Generic), 0);
+            return;
+        }
+
+        let cb: Box<FnBox(i32, i32) -> i32> = Box::new(|a, written| {
+            cb(if a >= 0 {
+                Ok(a)
+            } else {
+                Err(error::Io::from_code(a))
+            }, written as usize);
+            0
+        });
+        let (cb, raw_ctx) = cb.wrap_callback();
+
+        unsafe {
+            __ice_uds_write(
+                self.handle,
+                &data[0],
+                data.len(),
+                cb,
+                raw_ctx
+            )
+        }
+    }
+
+    pub fn read<F: FnOnce(IoResult<UnixBuffer>) + 'static>(&self, len: usize, cb: F) {
+        let cb: Box<FnBox(i32) -> i32> = Box::new(|a| {
+            cb(if a >= 0 {
+                Ok(UnixBuffer { handle: a })
+            } else {
+                Err(error::Io::from_code(a))
+            });
+            0
+        });
+        let (cb, raw_ctx) = cb.wrap_callback();
+
+        unsafe {
+            __ice_uds_read(
+                self.handle,
+                len,
+                cb,
+                raw_ctx
+            )
+        }
+    }
+}
+
+/// Listens on the socket file at `path`, which is removed again by
+/// `stop_listen_unix`. Returns the listener id, or a negative error code.
+pub fn listen_unix<T: Fn(UnixStream) + 'static>(
+    path: &str,
+    cb: T
+) -> i32 {
+    let cb: Box<Fn(i32) -> i32> = Box::new(move |stream_id| {
+        if stream_id >= 0 {
+            cb(UnixStream {
+                inner: Rc::new(UnixStreamImpl {
+                    handle: stream_id
+                })
+            });
+        }
+        0
+    });
+    let (cb, raw_ctx) = cb.wrap_callback();
+
+    unsafe {
+        let path = path.as_bytes();
+        __ice_uds_listen(
+            &path[0],
+            path.len(),
+            cb,
+            raw_ctx
+        )
+    }
+}
+
+pub fn stop_listen_unix(listener_id: i32) -> IoResult<()> {
+    let ret = unsafe {
+        __ice_uds_stop_listen(listener_id)
+    };
+    if ret >= 0 {
+        Ok(())
+    } else {
+        Err(error::Io::from_code(ret))
+    }
+}
+
+pub fn connect_unix<F: FnOnce(IoResult<UnixStream>) + 'static>(
+    path: &str,
+    cb: F
+) {
+    let cb: Box<FnBox(i32) -> i32> = Box::new(move |stream_id| {
+        cb(if stream_id >= 0 {
+            Ok(UnixStream {
+                inner: Rc::new(UnixStreamImpl {
+                    handle: stream_id
+                })
+            })
+        } else {
+            Err(error::Io::from_code(stream_id))
+        });
+
+        0
+    });
+    let (cb, raw_ctx) = cb.wrap_callback();
+
+    unsafe {
+        let path = path.as_bytes();
+        __ice_uds_connect(
+            &path[0],
+            path.len(),
+            cb,
+            raw_ctx
+        )
+    }
+}
+
+pub struct UdpSocket {
+    handle: i32
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        unsafe {
+            __ice_udp_destroy(self.handle);
+        }
+    }
+}
+
+fn take_udp_buffer(buffer_id: i32) -> Vec<u8> {
+    let len = unsafe { __ice_udp_buffer_len(buffer_id) };
+    assert!(len >= 0);
+
+    let mut buffer: Vec<u8> = vec! [ 0; len as usize ];
+    let real_len = unsafe { __ice_udp_take_buffer(
+        buffer_id,
+        buffer.as_mut_ptr(),
+        len as usize
+    ) };
+    assert!(real_len >= 0);
+    buffer.truncate(real_len as usize);
+    buffer
+}
+
+impl UdpSocket {
+    /// Binds a UDP socket to `addr` (in the format `ip:port`).
+    ///
+    /// The application must have `UdpBindAny` or `UdpBind(addr)` permissions.
+    pub fn bind(addr: &str) -> IoResult<UdpSocket> {
+        let addr = addr.as_bytes();
+        let ret = unsafe {
+            __ice_udp_bind(
+                addr.as_ptr(),
+                addr.len()
+            )
+        };
+        if ret >= 0 {
+            Ok(UdpSocket {
+                handle: ret
+            })
+        } else {
+            Err(error::Io::from_code(ret))
+        }
+    }
+
+    pub fn send_to<F: FnOnce(IoResult<usize>) + 'static>(&self, data: &[u8], addr: &str, cb: F) {
+        let cb: Box<FnBox(i32) -> i32> = Box::new(|a| {
+            cb(if a >= 0 {
+                Ok(a as usize)
+            } else {
+                Err(error::Io::from_code(a))
+            });
+            0
+        });
+        let (cb, raw_ctx) = cb.wrap_callback();
+
+        let addr = addr.as_bytes();
+        unsafe {
+            __ice_udp_send_to(
+                self.handle,
+                data.as_ptr(),
+                data.len(),
+                addr.as_ptr(),
+                addr.len(),
+                cb,
+                raw_ctx
+            )
+        }
+    }
+
+    /// Receives a datagram of at most `len` bytes, together with the
+    /// address of its sender.
+    pub fn recv_from<F: FnOnce(IoResult<(Vec<u8>, String)>) + 'static>(&self, len: usize, cb: F) {
+        let cb: Box<FnBox(i32, i32) -> i32> = Box::new(move |data_id, addr_id| {
+            cb(if data_id >= 0 {
+                let data = take_udp_buffer(data_id);
+                let addr = take_udp_buffer(addr_id);
+                Ok((data, String::from_utf8_lossy(&addr).into_owned()))
+            } else {
+                Err(error::Io::from_code(data_id))
+            });
+            0
+        });
+        let (cb, raw_ctx) = cb.wrap_callback();
+
+        unsafe {
+            __ice_udp_recv_from(
+                self.handle,
+                len,
+                cb,
+                raw_ctx
+            )
+        }
+    }
+}
+
+/// A response received by `http_request`.
+pub struct HttpResponse {
+    handle: i32
+}
+
+impl Drop for HttpResponse {
+    fn drop(&mut self) {
+        unsafe {
+            __ice_httpc_destroy(self.handle);
+        }
+    }
+}
+
+fn take_http_buffer(buffer_id: i32) -> Vec<u8> {
+    let len = unsafe { __ice_httpc_buffer_len(buffer_id) };
+    assert!(len >= 0);
+
+    let mut buffer: Vec<u8> = vec! [ 0; len as usize ];
+    let real_len = unsafe { __ice_httpc_take_buffer(
+        buffer_id,
+        buffer.as_mut_ptr(),
+        len as usize
+    ) };
+    assert!(real_len >= 0);
+    buffer.truncate(real_len as usize);
+    buffer
+}
+
+/// Sends an HTTP request. `headers` contains `Name: value` lines.
+///
+/// Only `http://` URLs are supported, and the application must have the
+/// `HttpRequest(host)` permission.
+pub fn http_request<F: FnOnce(IoResult<HttpResponse>) + 'static>(
+    method: &str,
+    url: &str,
+    headers: &str,
+    body: &[u8],
+    cb: F
+) {
+    let cb: Box<FnBox(i32) -> i32> = Box::new(|a| {
+        cb(if a >= 0 {
+            Ok(HttpResponse { handle: a })
+        } else {
+            Err(error::Io::from_code(a))
+        });
+        0
+    });
+    let (cb, raw_ctx) = cb.wrap_callback();
+
+    unsafe {
+        __ice_httpc_request(
+            method.as_ptr(),
+            method.len(),
+            url.as_ptr(),
+            url.len(),
+            headers.as_ptr(),
+            headers.len(),
+            body.as_ptr(),
+            body.len(),
+            cb,
+            raw_ctx
+        )
+    }
+}
+
+impl HttpResponse {
+    pub fn status(&self) -> u16 {
+        unsafe {
+            __ice_httpc_status(self.handle) as u16
+        }
+    }
+
+    /// Returns the response headers as `Name: value` lines.
+    pub fn headers(&self) -> String {
+        let buffer_id = unsafe { __ice_httpc_headers(self.handle) };
+        String::from_utf8_lossy(&take_http_buffer(buffer_id)).into_owned()
+    }
+
+    /// Reads the whole response body. The body can only be read once.
+    pub fn read_body<F: FnOnce(IoResult<Vec<u8>>) + 'static>(&self, cb: F) {
+        let cb: Box<FnBox(i32) -> i32> = Box::new(|a| {
+            cb(if a >= 0 {
+                Ok(take_http_buffer(a))
+            } else {
+                Err(error::Io::from_code(a))
+            });
+            0
+        });
+        let (cb, raw_ctx) = cb.wrap_callback();
+
+        unsafe {
+            __ice_httpc_read_body(
+                self.handle,
+                cb,
+                raw_ctx
+            )
+        }
+    }
+}
+
 pub fn file_open(path: &str, mode: &str) -> IoResult<i32> {
     let path = path.as_bytes();
     let mode = mode.as_bytes();
@@ -401,7 +1259,75 @@ pub fn file_open(path: &str, mode: &str) -> IoResult<i32> {
     if ret >= 0 {
         Ok(ret)
     } else {
-        Err(error::Io::Generic)
+        Err(error::Io::from_code(ret))
+    }
+}
+
+fn take_file_buffer(buffer_id: i32) -> Vec<u8> {
+    let len = unsafe { __ice_file_buffer_len(buffer_id) };
+    assert!(len >= 0);
+
+    let mut buffer: Vec<u8> = vec! [ 0; len as usize ];
+    let real_len = unsafe { __ice_file_take_buffer(
+        buffer_id,
+        buffer.as_mut_ptr(),
+        len as usize
+    ) };
+    assert!(real_len >= 0);
+    buffer.truncate(real_len as usize);
+    buffer
+}
+
+/// Reads the whole file at `path` without blocking the application.
+///
+/// The application must have `FileOpenReadOnlyAny`, `FileOpenReadWriteAny`
+/// or an `FsRead` permission covering `path`.
+pub fn read_file<F: FnOnce(IoResult<Vec<u8>>) + 'static>(path: &str, cb: F) {
+    let cb: Box<FnBox(i32) -> i32> = Box::new(|a| {
+        cb(if a >= 0 {
+            Ok(take_file_buffer(a))
+        } else {
+            Err(error::Io::from_code(a))
+        });
+        0
+    });
+    let (cb, raw_ctx) = cb.wrap_callback();
+
+    unsafe {
+        __ice_file_read_file(
+            path.as_ptr(),
+            path.len(),
+            cb,
+            raw_ctx
+        )
+    }
+}
+
+/// Creates or truncates the file at `path` and writes `data` to it without
+/// blocking the application.
+///
+/// The application must have `FileOpenReadWriteAny` or an `FsWrite`
+/// permission covering `path`.
+pub fn write_file<F: FnOnce(IoResult<usize>) + 'static>(path: &str, data: &[u8], cb: F) {
+    let cb: Box<FnBox(i32) -> i32> = Box::new(|a| {
+        cb(if a >= 0 {
+            Ok(a as usize)
+        } else {
+            Err(error::Io::from_code(a))
+        });
+        0
+    });
+    let (cb, raw_ctx) = cb.wrap_callback();
+
+    unsafe {
+        __ice_file_write_file(
+            path.as_ptr(),
+            path.len(),
+            data.as_ptr(),
+            data.len(),
+            cb,
+            raw_ctx
+        )
     }
 }
 
@@ -424,7 +1350,7 @@ pub fn file_read(fd: i32, out: &mut [u8]) -> IoResult<usize> {
     if ret >= 0 {
         Ok(ret as usize)
     } else {
-        Err(error::Io::Generic)
+        Err(error::Io::from_code(ret))
     }
 }
 
@@ -441,7 +1367,7 @@ pub fn file_write(fd: i32, data: &[u8]) -> IoResult<usize> {
     if ret >= 0 {
         Ok(ret as usize)
     } else {
-        Err(error::Io::Generic)
+        Err(error::Io::from_code(ret))
     }
 }
 
@@ -452,7 +1378,7 @@ pub fn file_flush(fd: i32) -> IoResult<()> {
     if ret >= 0 {
         Ok(())
     } else {
-        Err(error::Io::Generic)
+        Err(error::Io::from_code(ret))
     }
 }
 
@@ -473,6 +1399,6 @@ pub fn file_seek(fd: i32, from: SeekFrom) -> IoResult<u64> {
     if ret >= 0 {
         Ok(ret as u64)
     } else {
-        Err(error::Io::Generic)
+        Err(error::Io::from_code(ret as i32))
     }
 }
@@ -1,5 +1,6 @@
 use std::collections::BTreeSet;
-use std::path::Path;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Component, Path, PathBuf};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Config {
@@ -17,6 +18,12 @@ pub struct ApplicationConfig {
     pub memory: AppMemoryConfig,
     #[serde(default)]
     pub optimize: bool,
+    /// Count namespace calls and time async operations for this app.
+    #[serde(default)]
+    pub collect_metrics: bool,
+    /// Host environment variables the app may read.
+    #[serde(default)]
+    pub allowed_env: Vec<String>,
     #[serde(skip)]
     pub metadata: AppMetadata
 }
@@ -29,12 +36,14 @@ pub struct AppMetadata {
     pub bin: String
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
 pub struct AppMemoryConfig {
     pub min: usize,
     pub max: usize
 }
 
+const WASM_PAGE_SIZE: usize = 65536;
+
 impl Default for AppMemoryConfig {
     fn default() -> AppMemoryConfig {
         AppMemoryConfig {
@@ -47,23 +56,201 @@ impl Default for AppMemoryConfig {
 #[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Ord, PartialOrd)]
 pub enum AppPermission {
     Timer,
+    ReadClock,
     TcpListen(String /* address */),
     TcpListenAny,
     TcpConnect(String),
     TcpConnectAny,
+    UdpBind(String /* address */),
+    UdpBindAny,
+    HttpRequest(String /* host */),
     FileOpenReadOnlyAny,
-    FileOpenReadWriteAny
+    FileOpenReadWriteAny,
+    FsRead(String /* path prefix */),
+    FsWrite(String /* path prefix */),
+    UnixSocket(String /* path prefix */),
+    MessageApp(String /* app name */),
+    // Not checked by the host itself; for namespaces registered by
+    // embedders (see `CustomNamespaceDef`).
+    Custom(String)
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+impl AppPermission {
+    /// Returns whether holding `self` allows `requested`.
+    ///
+    /// Address permissions may use `*` for the host or the port, a port
+    /// range like `8000-8100`, and, for `TcpConnect`, a CIDR block like
+    /// `10.0.0.0/8` as the host. Without these, the address must match
+    /// exactly. IPv6 hosts are written in brackets, as in `[::1]:8080`, and
+    /// compared as addresses, so `[::1]` also matches `[0:0:0:0:0:0:0:1]`.
+    ///
+    /// `FsRead`, `FsWrite` and `UnixSocket` grant access to canonical paths
+    /// under their directory prefix.
+    pub fn grants(&self, requested: &AppPermission) -> bool {
+        use self::AppPermission::*;
+
+        match (self, requested) {
+            (&TcpListen(ref p), &TcpListen(ref a)) => address_matches(p, a, false),
+            (&TcpConnect(ref p), &TcpConnect(ref a)) => address_matches(p, a, true),
+            (&UdpBind(ref p), &UdpBind(ref a)) => address_matches(p, a, false),
+            (&FsRead(ref p), &FsRead(ref a)) => path_under_prefix(p, a),
+            (&FsWrite(ref p), &FsWrite(ref a)) => path_under_prefix(p, a),
+            (&UnixSocket(ref p), &UnixSocket(ref a)) => path_under_prefix(p, a),
+            _ => self == requested
+        }
+    }
+}
+
+fn path_under_prefix(prefix: &str, path: &str) -> bool {
+    let path = Path::new(path);
+    // Requested paths are canonical. A `..` left in one could still lead
+    // out of the prefix, since `starts_with` compares components only.
+    if path.components().any(|c| c == Component::ParentDir) {
+        return false;
+    }
+
+    let prefix = ::std::fs::canonicalize(prefix).unwrap_or_else(|_| PathBuf::from(prefix));
+    path.starts_with(&prefix)
+}
+
+fn address_matches(pattern: &str, addr: &str, allow_cidr: bool) -> bool {
+    if pattern == addr {
+        return true;
+    }
+
+    let (p_host, p_port) = match split_host_port(pattern) {
+        Some(v) => v,
+        None => return false
+    };
+    let (host, port) = match split_host_port(addr) {
+        Some(v) => v,
+        None => return false
+    };
+
+    host_matches(p_host, host, allow_cidr) && port_matches(p_port, port)
+}
+
+/// Splits `host:port` or `[v6-host]:port`. The brackets are stripped.
+/// An unbracketed host containing `:` is ambiguous and rejected.
+fn split_host_port(addr: &str) -> Option<(&str, &str)> {
+    if addr.starts_with('[') {
+        let end = addr.find("]:")?;
+        return Some((&addr[1..end], &addr[end + 2..]));
+    }
+
+    let i = addr.rfind(':')?;
+    let host = &addr[..i];
+    if host.contains(':') {
+        return None;
+    }
+    Some((host, &addr[i + 1..]))
+}
+
+fn host_matches(pattern: &str, host: &str, allow_cidr: bool) -> bool {
+    if pattern == "*" || pattern == host {
+        return true;
+    }
+
+    if let (Ok(p), Ok(h)) = (pattern.parse::<IpAddr>(), host.parse::<IpAddr>()) {
+        return p == h;
+    }
+
+    if allow_cidr && pattern.contains('/') {
+        return cidr_contains(pattern, host);
+    }
+
+    false
+}
+
+fn port_matches(pattern: &str, port: &str) -> bool {
+    if pattern == "*" || pattern == port {
+        return true;
+    }
+
+    let port: u16 = match port.parse() {
+        Ok(v) => v,
+        Err(_) => return false
+    };
+
+    let mut parts = pattern.splitn(2, '-');
+    let lo: u16 = match parts.next().and_then(|v| v.parse().ok()) {
+        Some(v) => v,
+        None => return false
+    };
+    let hi: u16 = match parts.next() {
+        Some(v) => match v.parse() {
+            Ok(v) => v,
+            Err(_) => return false
+        },
+        None => lo
+    };
+
+    lo <= port && port <= hi
+}
+
+fn cidr_contains(cidr: &str, host: &str) -> bool {
+    let mut parts = cidr.splitn(2, '/');
+    let network: IpAddr = match parts.next().and_then(|v| v.parse().ok()) {
+        Some(v) => v,
+        None => return false
+    };
+    let prefix_len: u32 = match parts.next().and_then(|v| v.parse().ok()) {
+        Some(v) => v,
+        None => return false
+    };
+    let host: IpAddr = match host.parse() {
+        Ok(v) => v,
+        Err(_) => return false
+    };
+
+    match (network, host) {
+        (IpAddr::V4(n), IpAddr::V4(h)) => {
+            if prefix_len > 32 {
+                return false;
+            }
+            let mask = if prefix_len == 0 { 0 } else { !0u32 << (32 - prefix_len) };
+            (u32::from(n) & mask) == (u32::from(h) & mask)
+        },
+        (IpAddr::V6(n), IpAddr::V6(h)) => {
+            if prefix_len > 128 {
+                return false;
+            }
+            let to_u128 = |octets: [u8; 16]| octets.iter().fold(0u128, |acc, b| (acc << 8) | *b as u128);
+            let mask = if prefix_len == 0 { 0 } else { !0u128 << (128 - prefix_len) };
+            (to_u128(n.octets()) & mask) == (to_u128(h.octets()) & mask)
+        },
+        _ => false
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
 pub struct ServiceConfig {
-    pub kind: ServiceKind
+    pub kind: ServiceKind,
+    #[serde(default = "default_listen_addr")]
+    pub listen_addr: String,
+    /// Maximum number of connections served at once. Further connections
+    /// wait in the accept queue. Unlimited when absent. Built-in services
+    /// all run on the main event loop, so this doesn't add threads.
+    #[serde(default)]
+    pub max_connections: Option<usize>
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+fn default_listen_addr() -> String {
+    "0.0.0.0:80".into()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
 pub enum ServiceKind {
     Tcp,
-    Http
+    Http,
+    Https {
+        cert_path: String,
+        key_path: String
+    },
+    /// Serves stats in the Prometheus text format.
+    Metrics,
+    /// Liveness endpoint for load balancers. Does not run any app.
+    Health
 }
 
 fn read_and_parse_yaml_config<
@@ -91,6 +278,7 @@ fn read_and_parse_yaml_config<
 impl Config {
     pub fn from_file(path: &str) -> Config {
         let mut config: Config = read_and_parse_yaml_config(path);
+        config.expand_env_vars();
 
         for app in &mut config.applications {
             let app_root = app.path.clone();
@@ -99,6 +287,319 @@ impl Config {
             app.metadata = read_and_parse_yaml_config(metadata_path.to_str().unwrap());
         }
 
+        for service in &config.services {
+            service.check();
+        }
+
         config
     }
+
+    /// Checks the configuration for values that would otherwise fail deep
+    /// inside app instantiation. Returns a description of each problem found.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        for app in &self.applications {
+            if app.path.is_empty() {
+                problems.push(format!("Application `{}`: path is empty", app.name));
+            }
+
+            let mem = &app.memory;
+            if mem.min == 0 {
+                problems.push(format!("Application `{}`: memory.min must not be zero", app.name));
+            }
+            if mem.min > mem.max {
+                problems.push(format!(
+                    "Application `{}`: memory.min ({}) exceeds memory.max ({})",
+                    app.name, mem.min, mem.max
+                ));
+            }
+            if mem.min % WASM_PAGE_SIZE != 0 || mem.max % WASM_PAGE_SIZE != 0 {
+                problems.push(format!(
+                    "Application `{}`: memory.min and memory.max must be multiples of the page size ({})",
+                    app.name, WASM_PAGE_SIZE
+                ));
+            }
+        }
+
+        for service in &self.services {
+            // Parsed so that configs can be written ahead of time, but there
+            // is no HTTP server to put TLS in front of yet.
+            if let ServiceKind::Https { .. } = service.kind {
+                problems.push(format!(
+                    "Service on {}: Https services are not supported by this server",
+                    service.listen_addr
+                ));
+            }
+        }
+
+        problems
+    }
+
+    /// Expands `${VAR}` references to environment variables in path and
+    /// address fields. `$$` stands for a literal `$`.
+    fn expand_env_vars(&mut self) {
+        for app in &mut self.applications {
+            app.path = expand_env_vars(&app.path);
+        }
+
+        for service in &mut self.services {
+            service.listen_addr = expand_env_vars(&service.listen_addr);
+            if let ServiceKind::Https { ref mut cert_path, ref mut key_path } = service.kind {
+                *cert_path = expand_env_vars(cert_path);
+                *key_path = expand_env_vars(key_path);
+            }
+        }
+    }
+}
+
+fn expand_env_vars(text: &str) -> String {
+    let mut out = String::new();
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            out.push(c);
+            continue;
+        }
+
+        match chars.peek().cloned() {
+            Some('$') => {
+                chars.next();
+                out.push('$');
+            },
+            Some('{') => {
+                chars.next();
+
+                let mut name = String::new();
+                let mut terminated = false;
+                while let Some(c) = chars.next() {
+                    if c == '}' {
+                        terminated = true;
+                        break;
+                    }
+                    name.push(c);
+                }
+                if !terminated {
+                    panic!("Unterminated environment variable reference in `{}`", text);
+                }
+
+                let value = ::std::env::var(&name).unwrap_or_else(|_| {
+                    panic!("Environment variable `{}` referenced in configuration is not set", name)
+                });
+                out.push_str(&value);
+            },
+            _ => out.push('$')
+        }
+    }
+
+    out
+}
+
+impl ServiceConfig {
+    /// Makes sure the listen address is valid and files referenced by the
+    /// service are readable, so that problems show up at startup instead
+    /// of on the first connection.
+    fn check(&self) {
+        use std::fs::File;
+
+        if let Err(e) = self.listen_addr.parse::<SocketAddr>() {
+            panic!("Invalid listen address `{}` for {:?} service: {}", self.listen_addr, self.kind, e);
+        }
+
+        if self.max_connections == Some(0) {
+            panic!("max_connections for {:?} service must be at least 1", self.kind);
+        }
+
+        if let ServiceKind::Https { ref cert_path, ref key_path } = self.kind {
+            for path in &[cert_path, key_path] {
+                if let Err(e) = File::open(path) {
+                    panic!("Unable to open TLS certificate/key file {}: {:?}", path, e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_host_port_accepts_v4_and_bracketed_v6() {
+        assert_eq!(split_host_port("127.0.0.1:80"), Some(("127.0.0.1", "80")));
+        assert_eq!(split_host_port("example.com:*"), Some(("example.com", "*")));
+        assert_eq!(split_host_port("[::1]:8080"), Some(("::1", "8080")));
+        assert_eq!(split_host_port("[*]:80"), Some(("*", "80")));
+    }
+
+    #[test]
+    fn split_host_port_rejects_malformed_input() {
+        assert_eq!(split_host_port("127.0.0.1"), None);
+        assert_eq!(split_host_port("::1:8080"), None);
+        assert_eq!(split_host_port("[::1]"), None);
+        assert_eq!(split_host_port("[::1:8080"), None);
+    }
+
+    #[test]
+    fn host_matches_wildcard_exact_and_equivalent_ips() {
+        assert!(host_matches("*", "10.0.0.1", false));
+        assert!(host_matches("example.com", "example.com", false));
+        assert!(host_matches("::1", "0:0:0:0:0:0:0:1", false));
+        assert!(!host_matches("example.com", "example.org", false));
+        assert!(!host_matches("127.0.0.1", "127.0.0.2", false));
+    }
+
+    #[test]
+    fn host_matches_cidr_only_when_allowed() {
+        assert!(host_matches("10.0.0.0/8", "10.1.2.3", true));
+        assert!(!host_matches("10.0.0.0/8", "10.1.2.3", false));
+        assert!(!host_matches("10.0.0.0/8", "11.0.0.1", true));
+    }
+
+    #[test]
+    fn port_matches_wildcard_exact_and_ranges() {
+        assert!(port_matches("*", "1234"));
+        assert!(port_matches("80", "80"));
+        assert!(port_matches("8000-8100", "8000"));
+        assert!(port_matches("8000-8100", "8100"));
+        assert!(!port_matches("8000-8100", "8101"));
+        assert!(!port_matches("80", "81"));
+    }
+
+    #[test]
+    fn port_matches_rejects_malformed_input() {
+        assert!(!port_matches("8100-8000", "8050"));
+        assert!(!port_matches("80-", "80"));
+        assert!(!port_matches("-80", "80"));
+        assert!(!port_matches("abc", "80"));
+        assert!(!port_matches("80-90", "85x"));
+        assert!(!port_matches("0-70000", "80"));
+    }
+
+    #[test]
+    fn address_matches_combines_host_and_port() {
+        assert!(address_matches("0.0.0.0:8080", "0.0.0.0:8080", false));
+        assert!(address_matches("*:8000-8100", "127.0.0.1:8080", false));
+        assert!(address_matches("[::1]:*", "[::1]:443", false));
+        assert!(address_matches("10.0.0.0/8:443", "10.2.3.4:443", true));
+        assert!(!address_matches("10.0.0.0/8:443", "10.2.3.4:443", false));
+        assert!(!address_matches("*:80", "127.0.0.1:8080", false));
+        assert!(!address_matches("*:80", "not an address", false));
+    }
+
+    #[test]
+    fn cidr_contains_v4() {
+        assert!(cidr_contains("0.0.0.0/0", "203.0.113.7"));
+        assert!(cidr_contains("192.168.1.0/24", "192.168.1.255"));
+        assert!(!cidr_contains("192.168.1.0/24", "192.168.2.1"));
+        assert!(cidr_contains("192.168.1.5/32", "192.168.1.5"));
+        assert!(!cidr_contains("192.168.1.5/32", "192.168.1.6"));
+        assert!(!cidr_contains("192.168.1.0/33", "192.168.1.1"));
+    }
+
+    #[test]
+    fn cidr_contains_v6() {
+        assert!(cidr_contains("::/0", "2001:db8::1"));
+        assert!(cidr_contains("2001:db8::/32", "2001:db8:ffff::1"));
+        assert!(!cidr_contains("2001:db8::/32", "2001:db9::1"));
+        assert!(cidr_contains("2001:db8::1/128", "2001:db8::1"));
+        assert!(!cidr_contains("2001:db8::1/128", "2001:db8::2"));
+        assert!(!cidr_contains("2001:db8::/129", "2001:db8::1"));
+    }
+
+    #[test]
+    fn cidr_contains_rejects_mixed_families_and_malformed_input() {
+        assert!(!cidr_contains("0.0.0.0/0", "::1"));
+        assert!(!cidr_contains("::/0", "127.0.0.1"));
+        assert!(!cidr_contains("::/0", "::ffff:127.0.0.1x"));
+        assert!(!cidr_contains("10.0.0.0", "10.0.0.1"));
+        assert!(!cidr_contains("10.0.0.0/x", "10.0.0.1"));
+        assert!(!cidr_contains("10.0.0.0/8", "example.com"));
+    }
+
+    #[test]
+    fn path_under_prefix_matches_whole_components() {
+        // Doesn't exist, so the prefix is compared as written.
+        let root = "/nonexistent-ice-config-test";
+
+        assert!(path_under_prefix(&format!("{}/data", root), &format!("{}/data", root)));
+        assert!(path_under_prefix(&format!("{}/data", root), &format!("{}/data/a/b", root)));
+        assert!(!path_under_prefix(&format!("{}/data", root), &format!("{}/database", root)));
+        assert!(!path_under_prefix(&format!("{}/data", root), &format!("{}/database/a", root)));
+        assert!(!path_under_prefix(&format!("{}/data", root), root));
+    }
+
+    #[test]
+    fn path_under_prefix_rejects_parent_components() {
+        let root = "/nonexistent-ice-config-test";
+
+        assert!(!path_under_prefix(&format!("{}/data", root), &format!("{}/data/../etc/passwd", root)));
+        assert!(!path_under_prefix(&format!("{}/data", root), &format!("{}/data/a/../../database", root)));
+    }
+
+    #[test]
+    fn grants_uses_address_matching() {
+        use self::AppPermission::*;
+
+        assert!(TcpListen("*:8000-8100".into()).grants(&TcpListen("0.0.0.0:8001".into())));
+        assert!(TcpConnect("10.0.0.0/8:*".into()).grants(&TcpConnect("10.0.0.1:80".into())));
+        assert!(!TcpListen("10.0.0.0/8:*".into()).grants(&TcpListen("10.0.0.1:80".into())));
+        assert!(!TcpListen("*:*".into()).grants(&UdpBind("0.0.0.0:53".into())));
+    }
+
+    #[test]
+    #[should_panic(expected = "max_connections")]
+    fn service_rejects_zero_max_connections() {
+        ServiceConfig {
+            kind: ServiceKind::Health,
+            listen_addr: default_listen_addr(),
+            max_connections: Some(0)
+        }.check();
+    }
+
+    #[test]
+    fn validate_rejects_https_services() {
+        let service = |kind| ServiceConfig {
+            kind: kind,
+            listen_addr: default_listen_addr(),
+            max_connections: None
+        };
+        let mut config = Config {
+            applications: Vec::new(),
+            services: vec! [ service(ServiceKind::Health) ]
+        };
+        assert!(config.validate().is_empty());
+
+        config.services.push(service(ServiceKind::Https {
+            cert_path: "cert.pem".into(),
+            key_path: "key.pem".into()
+        }));
+        let problems = config.validate();
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("Https"));
+    }
+
+    #[test]
+    fn expand_env_vars_substitutes_and_escapes() {
+        ::std::env::set_var("ICE_CONFIG_TEST_PORT", "8080");
+        assert_eq!(expand_env_vars("0.0.0.0:${ICE_CONFIG_TEST_PORT}"), "0.0.0.0:8080");
+        assert_eq!(expand_env_vars("${ICE_CONFIG_TEST_PORT}${ICE_CONFIG_TEST_PORT}"), "80808080");
+        assert_eq!(expand_env_vars("price: $$5"), "price: $5");
+        assert_eq!(expand_env_vars("$${ICE_CONFIG_TEST_PORT}"), "${ICE_CONFIG_TEST_PORT}");
+        assert_eq!(expand_env_vars("/plain/path"), "/plain/path");
+    }
+
+    #[test]
+    #[should_panic(expected = "ICE_CONFIG_TEST_UNSET")]
+    fn expand_env_vars_rejects_unset_variable() {
+        ::std::env::remove_var("ICE_CONFIG_TEST_UNSET");
+        expand_env_vars("/apps/${ICE_CONFIG_TEST_UNSET}");
+    }
+
+    #[test]
+    #[should_panic(expected = "Unterminated")]
+    fn expand_env_vars_rejects_unterminated_reference() {
+        expand_env_vars("/apps/${ICE_CONFIG_TEST_PORT");
+    }
 }
@@ -4,9 +4,14 @@ use std::collections::BTreeMap;
 
 use config::Config;
 use lssa::control::Control;
+use lssa::namespace::CustomNamespaceDef;
 
 use futures::sync::mpsc::Sender;
 use futures::Sink;
+use tokio_threadpool;
+
+/// Upper bound on threads doing blocking file I/O for all apps together.
+const BLOCKING_POOL_SIZE: usize = 4;
 
 #[derive(Clone)]
 pub struct Container {
@@ -15,7 +20,9 @@ pub struct Container {
 
 pub struct ContainerImpl {
     pub config_state: RwLock<ConfigState>,
-    control_dispatcher: Mutex<Option<ControlDispatcher>>
+    control_dispatcher: Mutex<Option<ControlDispatcher>>,
+    custom_namespaces: RwLock<Vec<Arc<CustomNamespaceDef>>>,
+    blocking_pool: tokio_threadpool::ThreadPool
 }
 
 pub struct ControlDispatcher {
@@ -29,22 +36,53 @@ pub struct ConfigState {
 
 impl Container {
     pub fn new(config: Config) -> Container {
-        let app_name_to_id = config.applications.iter()
-            .enumerate()
-            .map(|(i, app)| (app.name.clone(), i))
-            .collect();
-
         Container {
             inner: Arc::new(ContainerImpl {
-                config_state: RwLock::new(ConfigState {
-                    config: config,
-                    app_name_to_id: app_name_to_id
-                }),
-                control_dispatcher: Mutex::new(None)
+                config_state: RwLock::new(ConfigState::new(config)),
+                control_dispatcher: Mutex::new(None),
+                custom_namespaces: RwLock::new(Vec::new()),
+                blocking_pool: tokio_threadpool::Builder::new()
+                    .pool_size(BLOCKING_POOL_SIZE)
+                    .name_prefix("ice-blocking-")
+                    .build()
             })
         }
     }
 
+    /// Adds a namespace that apps loaded from now on can import from.
+    /// Fails if `def` has an invalid prefix or one that is already taken by
+    /// another custom namespace. Built-in namespaces take precedence over
+    /// custom ones with the same prefix.
+    pub fn register_namespace(&self, def: CustomNamespaceDef) -> Result<(), ()> {
+        // The resolver splits `__ice_<prefix>_<name>` at the first `_`.
+        if def.prefix().is_empty() || def.prefix().contains('_') {
+            return Err(());
+        }
+
+        let mut namespaces = self.custom_namespaces.write().unwrap();
+        if namespaces.iter().any(|v| v.prefix() == def.prefix()) {
+            return Err(());
+        }
+        namespaces.push(Arc::new(def));
+        Ok(())
+    }
+
+    /// Threads for blocking work that would otherwise stall an app's event
+    /// loop. Work beyond the pool size waits in a queue.
+    pub fn blocking_pool(&self) -> tokio_threadpool::Sender {
+        self.blocking_pool.sender().clone()
+    }
+
+    pub fn custom_namespaces(&self) -> Vec<Arc<CustomNamespaceDef>> {
+        self.custom_namespaces.read().unwrap().clone()
+    }
+
+    /// Replaces the running config. App ids are re-assigned according to
+    /// the order of applications in `config`.
+    pub fn replace_config(&self, config: Config) {
+        *self.config_state.write().unwrap() = ConfigState::new(config);
+    }
+
     pub fn lookup_app_id_by_name(&self, name: &str) -> Option<usize> {
         let cs = self.config_state.read().unwrap();
         cs.app_name_to_id.get(name).map(|v| *v)
@@ -81,6 +119,20 @@ impl Deref for Container {
     }
 }
 
+impl ConfigState {
+    fn new(config: Config) -> ConfigState {
+        let app_name_to_id = config.applications.iter()
+            .enumerate()
+            .map(|(i, app)| (app.name.clone(), i))
+            .collect();
+
+        ConfigState {
+            config: config,
+            app_name_to_id: app_name_to_id
+        }
+    }
+}
+
 impl ControlDispatcher {
     pub fn new(sender: Sender<Control>) -> ControlDispatcher {
         ControlDispatcher {
@@ -0,0 +1,31 @@
+#![feature(nll)]
+
+extern crate wasm_core;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_yaml;
+extern crate slab;
+extern crate futures;
+extern crate tokio;
+extern crate ansi_term;
+extern crate chrono;
+extern crate tokio_io;
+extern crate tokio_threadpool;
+extern crate hyper;
+extern crate rand;
+extern crate net2;
+
+#[macro_use]
+pub mod logging;
+
+#[macro_use]
+pub mod lssa;
+
+mod container;
+pub mod config;
+pub mod server;
+mod services;
+
+pub use server::Server;
+pub use lssa::namespace::{CustomNamespaceDef, InvokeContext};
@@ -22,7 +22,8 @@ impl<'a> Logger<'a> {
         let (kind, text) = match msg {
             Message::Info(t) => (Green.paint("[INFO]").to_string(), t),
             Message::Warning(t) => (Yellow.paint("[WARNING]").to_string(), t),
-            Message::Error(t) => (Red.paint("[ERROR]").to_string(), t)
+            Message::Error(t) => (Red.paint("[ERROR]").to_string(), t),
+            Message::Debug(t) => (Blue.paint("[DEBUG]").to_string(), t)
         };
 
         println!("{} {} {}: {}", Cyan.bold().paint(date.as_str()), Style::new().bold().paint(kind), self.module_name, text);
@@ -32,48 +33,67 @@ impl<'a> Logger<'a> {
 pub enum Message {
     Info(String),
     Warning(String),
-    Error(String)
+    Error(String),
+    Debug(String)
 }
 
+#[macro_export]
 macro_rules! logger {
-    ($name:expr) => (::logging::Logger::new($name))
+    ($name:expr) => ($crate::logging::Logger::new($name))
 }
 
+#[macro_export]
 macro_rules! dinfo {
     ($logger:expr, $fmt:expr) => (
         $logger.log(
-            ::logging::Message::Info(format!($fmt))
+            $crate::logging::Message::Info(format!($fmt))
         )
     );
     ($logger:expr, $fmt:expr, $($arg:tt)*) => (
         $logger.log(
-            ::logging::Message::Info(format!($fmt, $($arg)*))
+            $crate::logging::Message::Info(format!($fmt, $($arg)*))
         )
     );
 }
 
+#[macro_export]
 macro_rules! dwarning {
     ($logger:expr, $fmt:expr) => (
         $logger.log(
-            ::logging::Message::Warning(format!($fmt))
+            $crate::logging::Message::Warning(format!($fmt))
         )
     );
     ($logger:expr, $fmt:expr, $($arg:tt)*) => (
         $logger.log(
-            ::logging::Message::Warning(format!($fmt, $($arg)*))
+            $crate::logging::Message::Warning(format!($fmt, $($arg)*))
         )
     );
 }
 
+#[macro_export]
+macro_rules! ddebug {
+    ($logger:expr, $fmt:expr) => (
+        $logger.log(
+            $crate::logging::Message::Debug(format!($fmt))
+        )
+    );
+    ($logger:expr, $fmt:expr, $($arg:tt)*) => (
+        $logger.log(
+            $crate::logging::Message::Debug(format!($fmt, $($arg)*))
+        )
+    );
+}
+
+#[macro_export]
 macro_rules! derror {
     ($logger:expr, $fmt:expr) => (
         $logger.log(
-            ::logging::Message::Error(format!($fmt))
+            $crate::logging::Message::Error(format!($fmt))
         )
     );
     ($logger:expr, $fmt:expr, $($arg:tt)*) => (
         $logger.log(
-            ::logging::Message::Error(format!($fmt, $($arg)*))
+            $crate::logging::Message::Error(format!($fmt, $($arg)*))
         )
     );
 }
@@ -1,7 +1,10 @@
 use std::rc::Rc;
+#[cfg(test)]
+use std::rc::Weak;
 use std::cell::Cell;
 use std::ops::Deref;
-use std::time::SystemTime;
+use std::panic::{self, AssertUnwindSafe};
+use std::time::{Instant, SystemTime};
 
 use chrono;
 
@@ -12,6 +15,10 @@ use container::Container;
 
 use super::resolver::LssaResolver;
 use super::stats::AppStats;
+use super::error::ErrorCode;
+use super::metrics::Metrics;
+use super::control::Control;
+use super::ns::msg::Mailbox;
 use config::AppPermission;
 
 // `inner` is intended to be used internally only and this should NOT be `Clone`.
@@ -22,10 +29,13 @@ pub struct Application {
 pub struct ApplicationImpl {
     pub(super) name: String,
     currently_inside: Cell<usize>,
+    faulted: Cell<bool>,
     module: Module,
     execution: ExecutionContext,
 
     start_time: SystemTime,
+    metrics: Option<Metrics>,
+    pub(super) mailbox: Mailbox,
 
     invoke0_fn: extern "C" fn (i64) -> i64,
     invoke1_fn: extern "C" fn (i64, i64) -> i64,
@@ -59,6 +69,14 @@ impl<'a> Drop for AppInsideHandle<'a> {
 
 pub type AppConfig = ::config::ApplicationConfig;
 
+#[derive(Debug, Eq, PartialEq)]
+pub enum InitError {
+    /// The initializer returned a non-zero value.
+    Failed(i64),
+    /// The initializer trapped or a host function panicked.
+    Panicked
+}
+
 impl Deref for Application {
     type Target = ApplicationImpl;
 
@@ -111,9 +129,16 @@ impl Application {
         let app = Rc::new(ApplicationImpl {
             name: config.name.clone(),
             currently_inside: Cell::new(0),
+            faulted: Cell::new(false),
             module: m,
             execution: vm,
             start_time: SystemTime::now(),
+            metrics: if config.collect_metrics {
+                Some(Metrics::new())
+            } else {
+                None
+            },
+            mailbox: Mailbox::new(),
             invoke0_fn: invoke0,
             invoke1_fn: invoke1,
             invoke2_fn: invoke2,
@@ -123,7 +148,8 @@ impl Application {
         });
 
         let mut resolver = LssaResolver::new(Rc::downgrade(&app));
-        resolver.init_default_namespaces();
+        resolver.init_default_namespaces(&app.container);
+        resolver.init_custom_namespaces(&app.container.custom_namespaces());
 
         app.execution.set_native_resolver(resolver);
 
@@ -132,26 +158,35 @@ impl Application {
         }
     }
 
-    pub fn initialize(&self, initializer_name: Option<&str>) {
+    pub fn initialize(&self, initializer_name: Option<&str>) -> Result<(), InitError> {
         let _inside = AppInsideHandle::new(self);
 
         let initializer_name = initializer_name.unwrap_or("__app_init");
 
         let entry_id = match self.module.lookup_exported_func(initializer_name) {
             Some(v) => v,
-            None => return
+            None => return Ok(())
         };
 
         let entry: extern "C" fn () -> i64 = unsafe {
             self.execution.get_function_checked(entry_id)
         };
 
-        let ret = entry();
-        if ret != 0 {
-            panic!("initialize: Initializer reported failure");
+        match panic::catch_unwind(|| entry()) {
+            Ok(0) => Ok(()),
+            Ok(ret) => Err(InitError::Failed(ret)),
+            Err(_) => {
+                self.faulted.set(true);
+                Err(InitError::Panicked)
+            }
         }
     }
 
+    #[cfg(test)]
+    pub fn downgrade(&self) -> Weak<ApplicationImpl> {
+        Rc::downgrade(&self.inner)
+    }
+
     pub fn stats(&self) -> AppStats {
         let dt: chrono::DateTime<chrono::Utc> = chrono::DateTime::from(self.start_time);
         let diff: chrono::Duration = chrono::Duration::from_std(
@@ -159,7 +194,8 @@ impl Application {
         ).unwrap();
         AppStats {
             start_time: dt.timestamp_millis(),
-            running_time: diff.num_milliseconds()
+            running_time: diff.num_milliseconds(),
+            metrics: self.metrics.as_ref().map(|m| m.snapshot())
         }
     }
 }
@@ -171,21 +207,101 @@ impl ApplicationImpl {
         let cs = self.container.config_state.read().unwrap();
         let app_config = &cs.config.applications[id];
 
-        if !app_config.metadata.permissions.contains(perm) {
+        if !app_config.metadata.permissions.contains(perm)
+            && !app_config.metadata.permissions.iter().any(|p| p.grants(perm)) {
             Err(())
         } else {
             Ok(())
         }
     }
 
+    /// Marks the app as faulted after a panic in `location`. A faulted app
+    /// receives no further callbacks and is unloaded by the app manager.
+    pub fn mark_faulted(&self, location: &str) {
+        if self.faulted.get() {
+            return;
+        }
+        self.faulted.set(true);
+
+        derror!(
+            logger!(&self.name),
+            "Panic in {}. The application will be unloaded.",
+            location
+        );
+        if self.container.dispatch_control(Control::RemoveFaulted).is_err() {
+            dwarning!(logger!(&self.name), "Unable to request unloading");
+        }
+    }
+
+    pub fn is_faulted(&self) -> bool {
+        self.faulted.get()
+    }
+
+    pub fn collects_metrics(&self) -> bool {
+        self.metrics.is_some()
+    }
+
+    pub fn record_call(&self, name: &'static str) {
+        if let Some(ref m) = self.metrics {
+            m.record_call(name);
+        }
+    }
+
+    /// Records the latency of an async operation that started at `start`.
+    pub fn record_latency(&self, name: &'static str, start: Instant) {
+        if let Some(ref m) = self.metrics {
+            m.record_latency(name, Instant::now().duration_since(start));
+        }
+    }
+
+    /// Calls `f` with the current config of this app.
+    pub fn with_config<R, F: FnOnce(&AppConfig) -> R>(&self, f: F) -> R {
+        let id = self.container.lookup_app_id_by_name(&self.name).unwrap();
+
+        let cs = self.container.config_state.read().unwrap();
+        f(&cs.config.applications[id])
+    }
+
+    /// Succeeds if the app holds any of `perms`. Otherwise logs which
+    /// permissions would have been accepted and returns `PermissionDenied`,
+    /// so that apps can tell a policy rejection from an I/O failure.
+    pub fn require_any_permission(&self, perms: &[AppPermission]) -> Result<(), ErrorCode> {
+        if perms.iter().any(|p| self.check_permission(p).is_ok()) {
+            return Ok(());
+        }
+
+        derror!(
+            logger!(&self.name),
+            "Permission denied. Any of the following is required: {:?}",
+            perms
+        );
+        Err(ErrorCode::PermissionDenied)
+    }
+
     #[allow(dead_code)]
     pub fn id(&self) -> usize {
         self.container.lookup_app_id_by_name(&self.name).unwrap()
     }
 
+    /// Runs a call into the app. A trap inside the app unwinds back to
+    /// here; the app is then marked as faulted instead of taking down the
+    /// whole app manager thread.
+    fn guarded_invoke<F: FnOnce() -> i64>(&self, location: &str, f: F) -> i32 {
+        if self.faulted.get() {
+            return ErrorCode::Generic.to_i32();
+        }
+        match panic::catch_unwind(AssertUnwindSafe(f)) {
+            Ok(ret) => ret as _,
+            Err(_) => {
+                self.mark_faulted(location);
+                ErrorCode::Generic.to_i32()
+            }
+        }
+    }
+
     #[allow(dead_code)]
     pub fn invoke0(&self, target: i32) -> i32 {
-        (self.invoke0_fn)((target as u32) as _) as _
+        self.guarded_invoke("invoke0", || (self.invoke0_fn)((target as u32) as _))
     }
 
     #[allow(dead_code)]
@@ -194,10 +310,10 @@ impl ApplicationImpl {
         target: i32,
         arg1: i32
     ) -> i32 {
-        (self.invoke1_fn)(
+        self.guarded_invoke("invoke1", || (self.invoke1_fn)(
             (target as u32) as _,
             (arg1 as u32) as _
-        ) as _
+        ))
     }
 
     #[allow(dead_code)]
@@ -207,11 +323,11 @@ impl ApplicationImpl {
         arg1: i32,
         arg2: i32
     ) -> i32 {
-        (self.invoke2_fn)(
+        self.guarded_invoke("invoke2", || (self.invoke2_fn)(
             (target as u32) as _,
             (arg1 as u32) as _,
             (arg2 as u32) as _
-        ) as _
+        ))
     }
 
     #[allow(dead_code)]
@@ -222,12 +338,12 @@ impl ApplicationImpl {
         arg2: i32,
         arg3: i32
     ) -> i32 {
-        (self.invoke3_fn)(
+        self.guarded_invoke("invoke3", || (self.invoke3_fn)(
             (target as u32) as _,
             (arg1 as u32) as _,
             (arg2 as u32) as _,
             (arg3 as u32) as _
-        ) as _
+        ))
     }
 
     #[allow(dead_code)]
@@ -239,12 +355,46 @@ impl ApplicationImpl {
         arg3: i32,
         arg4: i32
     ) -> i32 {
-        (self.invoke4_fn)(
+        self.guarded_invoke("invoke4", || (self.invoke4_fn)(
             (target as u32) as _,
             (arg1 as u32) as _,
             (arg2 as u32) as _,
             (arg3 as u32) as _,
             (arg4 as u32) as _
-        ) as _
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use container::Container;
+    use super::super::error::ErrorCode;
+    use super::super::testutil::*;
+    use super::InitError;
+
+    #[test]
+    fn trap_in_callback_faults_app() {
+        let container = Container::new(config_with_apps(&["trap"]));
+        let app = load_app(&container, "trap", &app_module(RETURN_ZERO, TRAP));
+        app.initialize(None).unwrap();
+        assert!(!app.is_faulted());
+
+        assert_eq!(app.invoke2(0, 0, 0), ErrorCode::Generic.to_i32());
+        assert!(app.is_faulted());
+
+        // No further calls reach the app.
+        assert_eq!(app.invoke0(0), ErrorCode::Generic.to_i32());
+    }
+
+    #[test]
+    fn initializer_failure_is_reported() {
+        let container = Container::new(config_with_apps(&["fail"]));
+
+        let app = load_app(&container, "fail", &app_module(RETURN_ONE, TRAP));
+        assert_eq!(app.initialize(None), Err(InitError::Failed(1)));
+
+        let app = load_app(&container, "fail", &app_module(TRAP, TRAP));
+        assert_eq!(app.initialize(None), Err(InitError::Panicked));
+        assert!(app.is_faulted());
     }
 }
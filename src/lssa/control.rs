@@ -1,8 +1,12 @@
 use super::event::EventInfo;
 use super::stats::StatsRequest;
+use config::Config;
 
 #[allow(dead_code)]
 pub enum Control {
     Event(EventInfo),
-    Stats(StatsRequest)
+    Stats(StatsRequest),
+    ReloadConfig(Config),
+    // Sent by an app that faulted, so that it gets unloaded promptly.
+    RemoveFaulted
 }
@@ -1,8 +1,14 @@
 use wasm_core::value::Value;
 
+/// Error codes passed to apps, as return values or callback arguments.
+///
+/// Apps receive the negated value (see `to_i32`), so that any negative
+/// result is an error and non-negative results stay free for lengths and
+/// handles. The numbering is part of the app ABI and is mirrored by
+/// `ia::error::Io`; only append new variants.
 #[allow(dead_code)]
 #[repr(i32)]
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum ErrorCode {
     Success = 0,
 
@@ -15,7 +21,14 @@ pub enum ErrorCode {
     InvalidInput = 6,
     BindFail = 7,
 
-    NotFound = 8
+    NotFound = 8,
+    TimedOut = 9,
+
+    ConnectionReset = 10,
+    ConnectionRefused = 11,
+    BrokenPipe = 12,
+
+    TooLarge = 13
 }
 
 impl ErrorCode {
@@ -36,6 +49,12 @@ impl From<::std::io::ErrorKind> for ErrorCode {
             NotFound => ErrorCode::NotFound,
             PermissionDenied => ErrorCode::PermissionDenied,
             InvalidInput => ErrorCode::InvalidInput,
+            TimedOut => ErrorCode::TimedOut,
+            UnexpectedEof => ErrorCode::Eof,
+            ConnectionReset | ConnectionAborted => ErrorCode::ConnectionReset,
+            ConnectionRefused => ErrorCode::ConnectionRefused,
+            BrokenPipe => ErrorCode::BrokenPipe,
+            AddrInUse | AddrNotAvailable => ErrorCode::BindFail,
             _ => ErrorCode::Generic
         }
     }
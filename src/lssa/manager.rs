@@ -1,6 +1,7 @@
 use wasm_core::trans;
 use super::app::{Application, AppConfig};
 use container::Container;
+use config::Config;
 use super::control::Control;
 use super::stats::{Stats, AppStats};
 use futures::Sink;
@@ -9,20 +10,47 @@ use std::collections::BTreeMap;
 
 pub struct AppManager {
     container: Container,
-    apps: Vec<Application>
+    apps: BTreeMap<usize, Application>
 }
 
 impl AppManager {
     pub fn new(container: Container) -> AppManager {
         AppManager {
             container: container,
-            apps: vec! []
+            apps: BTreeMap::new()
         }
     }
 
     fn add(&mut self, app_id: usize, app: Application) {
-        assert_eq!(self.apps.len(), app_id);
-        self.apps.push(app);
+        let prev = self.apps.insert(app_id, app);
+        assert!(prev.is_none());
+    }
+
+    /// Reads the app binary from `config.path` and loads it.
+    pub fn load_from_config(&mut self, app_id: usize, config: &AppConfig) {
+        use std::fs::File;
+        use std::io::Read;
+
+        let mut code_file = match File::open(
+            &::std::path::Path::new(
+                &config.path
+            ).join(&config.metadata.bin).to_str().unwrap()
+        ) {
+            Ok(v) => v,
+            Err(e) => {
+                dwarning!(
+                    logger!("AppManager::load_from_config"),
+                    "Unable to load app `{}`: {:?}",
+                    config.name,
+                    e
+                );
+                return;
+            }
+        };
+        let mut code: Vec<u8> = Vec::new();
+        code_file.read_to_end(&mut code).unwrap();
+
+        self.load(&code, app_id, config.clone());
     }
 
     pub fn load(&mut self, code: &[u8], app_id: usize, config: AppConfig) {
@@ -42,7 +70,15 @@ impl AppManager {
         );
         dinfo!(logger, "Application {} loaded", app.name);
 
-        app.initialize(None);
+        if let Err(e) = app.initialize(None) {
+            derror!(
+                logger,
+                "Unable to initialize application {}: {:?}",
+                app.name,
+                e
+            );
+            return;
+        }
         dinfo!(
             logger,
             "Application {} initialized. Total time: {}ms",
@@ -57,22 +93,203 @@ impl AppManager {
         self.add(app_id, app);
     }
 
-    pub fn dispatch_control(&self, c: Control) {
+    /// Switches to `new_config`, reloading only the apps whose `path` or
+    /// `memory` changed. Apps removed from the config are dropped; the
+    /// rest keep running with their state intact.
+    pub fn reload(&mut self, new_config: Config) {
+        let logger = logger!("AppManager::reload");
+
+        let old_config = self.container.config_state.read().unwrap().config.clone();
+        let mut old_apps = ::std::mem::replace(&mut self.apps, BTreeMap::new());
+
+        // Apps look up their own id by name, so the new mapping must be in
+        // place before any of them runs again.
+        self.container.replace_config(new_config.clone());
+
+        for (i, app_config) in new_config.applications.iter().enumerate() {
+            let old_id = old_config.applications.iter()
+                .position(|v| v.name == app_config.name);
+            let unchanged = old_id.map(|old_id| {
+                let old = &old_config.applications[old_id];
+                old.path == app_config.path && old.memory == app_config.memory
+            }).unwrap_or(false);
+
+            if let Some(app) = old_id.and_then(|old_id| old_apps.remove(&old_id)) {
+                if unchanged {
+                    self.add(i, app);
+                    continue;
+                }
+
+                // Stop the old instance first, so that it releases its
+                // listeners and sockets before the new one starts.
+                dinfo!(logger, "Application {} stopped", app.name);
+                drop(app);
+            }
+
+            dinfo!(logger, "Reloading application {}", app_config.name);
+            self.load_from_config(i, app_config);
+        }
+
+        for (_, app) in old_apps {
+            dinfo!(logger, "Application {} stopped", app.name);
+        }
+    }
+
+    /// Drops apps that panicked in a host function, which also closes their
+    /// sockets and listeners. The other apps are not affected.
+    fn remove_faulted(&mut self) {
+        let faulted: Vec<usize> = self.apps.iter()
+            .filter(|&(_, app)| app.is_faulted())
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in faulted {
+            let app = self.apps.remove(&id).unwrap();
+            dwarning!(
+                logger!("AppManager::remove_faulted"),
+                "Application {} unloaded after a fault",
+                app.name
+            );
+        }
+    }
+
+    pub fn dispatch_control(&mut self, c: Control) {
         match c {
             Control::Event(ev) => {
-                let app = &self.apps[ev.app_id];
+                let app = match self.apps.get(&ev.app_id) {
+                    Some(v) => v,
+                    None => {
+                        dwarning!(
+                            logger!("AppManager::dispatch_control"),
+                            "Dropping event for unknown app id {}",
+                            ev.app_id
+                        );
+                        return;
+                    }
+                };
                 ev.notify(app);
             },
             Control::Stats(mut req) => {
                 let mut stats: BTreeMap<String, AppStats> = BTreeMap::new();
-                for app in &self.apps {
+                for app in self.apps.values() {
                     let name = app.name.clone();
                     stats.insert(name, app.stats());
                 }
-                req.feedback.start_send(Stats {
+                // The requester may have given up waiting already.
+                if req.feedback.start_send(Stats {
                     applications: stats
-                }).unwrap();
+                }).is_err() {
+                    dwarning!(
+                        logger!("AppManager::dispatch_control"),
+                        "Stats requester went away before the reply"
+                    );
+                }
+            },
+            Control::ReloadConfig(config) => {
+                self.reload(config);
+            },
+            Control::RemoveFaulted => {
+                self.remove_faulted();
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+    use std::rc::{Rc, Weak};
+    use container::Container;
+    use config::Config;
+    use futures::sync::mpsc;
+    use super::super::app::ApplicationImpl;
+    use super::super::control::Control;
+    use super::super::stats::StatsRequest;
+    use super::super::testutil::*;
+    use super::AppManager;
+
+    fn app_dir(name: &str) -> PathBuf {
+        let dir = ::std::env::temp_dir().join(format!(
+            "ice-reload-test-{}-{}",
+            ::std::process::id(),
+            name
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("app.wasm"), app_module(RETURN_ZERO, RETURN_ZERO)).unwrap();
+        dir
+    }
+
+    fn config_in(dir: &PathBuf, names: &[&str]) -> Config {
+        let mut config = config_with_apps(names);
+        for app in &mut config.applications {
+            app.path = dir.to_str().unwrap().to_string();
+        }
+        config
+    }
+
+    fn instance(manager: &AppManager, id: usize) -> Weak<ApplicationImpl> {
+        manager.apps.get(&id).unwrap().downgrade()
+    }
+
+    #[test]
+    fn reload_restarts_only_changed_apps() {
+        let dir = app_dir("changed");
+        let config = config_in(&dir, &["a", "b", "c"]);
+        let container = Container::new(config.clone());
+
+        let mut manager = AppManager::new(container.clone());
+        for (i, app) in config.applications.iter().enumerate() {
+            manager.load_from_config(i, app);
+        }
+        let a = instance(&manager, 0);
+        let b = instance(&manager, 1);
+        let c = instance(&manager, 2);
+
+        // `a` is dropped, `b` keeps running and `c` gets a new memory limit.
+        let mut new_config = config_in(&dir, &["b", "c"]);
+        new_config.applications[1].memory.max *= 2;
+        manager.reload(new_config);
+
+        assert_eq!(manager.apps.len(), 2);
+        assert!(a.upgrade().is_none());
+        assert!(Rc::ptr_eq(&instance(&manager, 0).upgrade().unwrap(), &b.upgrade().unwrap()));
+        assert!(c.upgrade().is_none());
+        assert_eq!(manager.apps.get(&1).unwrap().name, "c");
+        assert_eq!(container.lookup_app_id_by_name("a"), None);
+        assert_eq!(container.lookup_app_id_by_name("c"), Some(1));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reload_skips_app_that_fails_to_initialize() {
+        let dir = app_dir("failing");
+        let config = config_in(&dir, &["a"]);
+        let container = Container::new(config.clone());
+
+        let mut manager = AppManager::new(container.clone());
+        manager.load_from_config(0, &config.applications[0]);
+
+        fs::write(dir.join("app.wasm"), app_module(TRAP, RETURN_ZERO)).unwrap();
+        let mut new_config = config.clone();
+        new_config.applications[0].memory.max *= 2;
+        manager.reload(new_config);
+
+        assert!(manager.apps.is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stats_reply_to_dropped_requester_is_ignored() {
+        let container = Container::new(config_with_apps(&[]));
+        let mut manager = AppManager::new(container);
+
+        let (tx, rx) = mpsc::channel(1);
+        drop(rx);
+        manager.dispatch_control(Control::Stats(StatsRequest {
+            feedback: tx
+        }));
+    }
+}
@@ -0,0 +1,61 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Per-app counters for namespace calls and async operation latency.
+///
+/// Only allocated for apps with `collect_metrics` set, so that apps
+/// without it pay nothing beyond a flag check per call.
+pub struct Metrics {
+    calls: RefCell<BTreeMap<&'static str, u64>>,
+    latency: RefCell<BTreeMap<&'static str, LatencyStats>>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct LatencyStats {
+    pub count: u64,
+    pub total_micros: u64,
+    pub max_micros: u64
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MetricsSnapshot {
+    pub calls: BTreeMap<String, u64>,
+    pub latency: BTreeMap<String, LatencyStats>
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            calls: RefCell::new(BTreeMap::new()),
+            latency: RefCell::new(BTreeMap::new())
+        }
+    }
+
+    pub fn record_call(&self, name: &'static str) {
+        *self.calls.borrow_mut().entry(name).or_insert(0) += 1;
+    }
+
+    pub fn record_latency(&self, name: &'static str, d: Duration) {
+        let micros = d.as_secs() * 1000000 + (d.subsec_nanos() / 1000) as u64;
+
+        let mut latency = self.latency.borrow_mut();
+        let entry = latency.entry(name).or_insert_with(LatencyStats::default);
+        entry.count += 1;
+        entry.total_micros += micros;
+        if micros > entry.max_micros {
+            entry.max_micros = micros;
+        }
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            calls: self.calls.borrow().iter()
+                .map(|(k, v)| (k.to_string(), *v))
+                .collect(),
+            latency: self.latency.borrow().iter()
+                .map(|(k, v)| (k.to_string(), v.clone()))
+                .collect()
+        }
+    }
+}
@@ -8,5 +8,9 @@ mod resolver;
 pub mod event;
 pub mod control;
 pub mod stats;
+pub mod metrics;
 pub mod ns;
 pub mod error;
+
+#[cfg(test)]
+pub mod testutil;
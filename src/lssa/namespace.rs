@@ -1,13 +1,98 @@
 use wasm_core::executor::{NativeEntry, GlobalStateProvider};
 use wasm_core::value::Value;
 use std::rc::Weak;
+use std::sync::Arc;
+use std::collections::BTreeMap;
 use super::app::ApplicationImpl;
+use super::error::ErrorCode;
 
 pub trait Namespace: 'static {
     fn prefix(&self) -> &str;
     fn dispatch(&self, field: &str) -> Option<NativeEntry>;
 }
 
+pub type CustomFunction = Arc<Fn(InvokeContext) -> Option<Value> + Send + Sync>;
+
+/// A namespace made of closures, registered at runtime with
+/// `Server::register_namespace` so that embedders can expose their own
+/// host functions. Apps call `name` in namespace `prefix` as the import
+/// `__ice_<prefix>_<name>`.
+///
+/// Custom functions are not gated by anything by default. To restrict one,
+/// check an `AppPermission::Custom(..)` permission with
+/// `app.require_any_permission` inside the closure, and grant it to apps
+/// in their config.
+pub struct CustomNamespaceDef {
+    prefix: String,
+    functions: BTreeMap<String, CustomFunction>
+}
+
+impl CustomNamespaceDef {
+    pub fn new(prefix: &str) -> CustomNamespaceDef {
+        CustomNamespaceDef {
+            prefix: prefix.to_string(),
+            functions: BTreeMap::new()
+        }
+    }
+
+    pub fn function<F>(mut self, name: &str, f: F) -> CustomNamespaceDef
+        where F: Fn(InvokeContext) -> Option<Value> + Send + Sync + 'static
+    {
+        self.functions.insert(name.to_string(), Arc::new(f));
+        self
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+}
+
+/// Per-app instance of a `CustomNamespaceDef`.
+pub struct CustomNamespace {
+    def: Arc<CustomNamespaceDef>,
+    app: Weak<ApplicationImpl>
+}
+
+impl CustomNamespace {
+    pub fn new(def: Arc<CustomNamespaceDef>, app: Weak<ApplicationImpl>) -> CustomNamespace {
+        CustomNamespace {
+            def: def,
+            app: app
+        }
+    }
+}
+
+impl Namespace for CustomNamespace {
+    fn prefix(&self) -> &str {
+        &self.def.prefix
+    }
+
+    fn dispatch(&self, field: &str) -> Option<NativeEntry> {
+        let f = self.def.functions.get(field)?.clone();
+        let location = format!("{}_{}", self.def.prefix, field);
+        let app = self.app.clone();
+
+        Some(Box::new(move |state, args| {
+            let ctx = InvokeContext {
+                state: state,
+                args: args,
+                app: &app
+            };
+            // Same containment as in `decl_namespace!`.
+            let result = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| (*f)(ctx)));
+            match result {
+                Ok(v) => Ok(v),
+                Err(_) => {
+                    if let Some(app) = app.upgrade() {
+                        app.mark_faulted(&location);
+                    }
+                    Ok(Some(ErrorCode::Generic.to_ret()))
+                }
+            }
+        }))
+    }
+}
+
 pub struct InvokeContext<'a> {
     pub state: &'a mut GlobalStateProvider,
     pub args: &'a [Value],
@@ -33,6 +118,32 @@ impl<'a> InvokeContext<'a> {
             self.extract_bytes(ptr_arg_index, len_arg_index)
         ).unwrap()
     }
+
+    /// Like `extract_bytes`, but returns `None` instead of panicking
+    /// when the range is out of the app's memory.
+    pub fn try_extract_bytes(&self, ptr_arg_index: usize, len_arg_index: usize) -> Option<&[u8]> {
+        let base = self.args[ptr_arg_index].get_i32().unwrap() as u32 as usize;
+        let len = self.args[len_arg_index].get_i32().unwrap() as u32 as usize;
+        let end = base.checked_add(len)?;
+        self.state.get_memory().get(base .. end)
+    }
+
+    /// Like `extract_bytes_mut`, but returns `None` instead of panicking
+    /// when the range is out of the app's memory.
+    pub fn try_extract_bytes_mut(&mut self, ptr_arg_index: usize, len_arg_index: usize) -> Option<&mut [u8]> {
+        let base = self.args[ptr_arg_index].get_i32().unwrap() as u32 as usize;
+        let len = self.args[len_arg_index].get_i32().unwrap() as u32 as usize;
+        let end = base.checked_add(len)?;
+        self.state.get_memory_mut().get_mut(base .. end)
+    }
+
+    /// Like `extract_str`, but returns `None` instead of panicking
+    /// when the range is out of bounds or not valid UTF-8.
+    pub fn try_extract_str(&self, ptr_arg_index: usize, len_arg_index: usize) -> Option<&str> {
+        ::std::str::from_utf8(
+            self.try_extract_bytes(ptr_arg_index, len_arg_index)?
+        ).ok()
+    }
 }
 
 macro_rules! decl_namespace {
@@ -69,15 +180,40 @@ macro_rules! decl_namespace {
                 let provider = self.provider.clone();
                 let app = self.app.clone();
 
+                // Decided once per resolved function, so that apps without
+                // metrics only pay for a flag check on each call.
+                let collect_metrics = app.upgrade()
+                    .map(|v| v.collects_metrics())
+                    .unwrap_or(false);
+
                 match field {
                     $(
                         stringify!($case) => Some(Box::new(move |state, args| {
+                            if collect_metrics {
+                                if let Some(app) = app.upgrade() {
+                                    app.record_call(concat!($prefix, "_", stringify!($case)));
+                                }
+                            }
                             let ctx = $crate::lssa::namespace::InvokeContext {
                                 state: state,
                                 args: args,
                                 app: &app
                             };
-                            Ok(provider.$case(ctx))
+                            // A panicking host function must not unwind through
+                            // JIT-compiled frames. The app is marked as faulted
+                            // instead, and unloaded by the app manager.
+                            let result = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+                                provider.$case(ctx)
+                            }));
+                            match result {
+                                Ok(v) => Ok(v),
+                                Err(_) => {
+                                    if let Some(app) = app.upgrade() {
+                                        app.mark_faulted(concat!($prefix, "_", stringify!($case)));
+                                    }
+                                    Ok(Some($crate::lssa::error::ErrorCode::Generic.to_ret()))
+                                }
+                            }
                         })),
                     )*
                     _ => None
@@ -0,0 +1,64 @@
+use super::super::namespace::InvokeContext;
+use super::super::error::ErrorCode;
+use super::tcp::BufferPool;
+use wasm_core::value::Value;
+use std::cell::RefCell;
+
+decl_namespace!(
+    EnvNs,
+    "env",
+    EnvImpl,
+    get_env,
+    release_buffer,
+    take_buffer,
+    buffer_len
+);
+
+pub struct EnvImpl {
+    buffers: RefCell<BufferPool>
+}
+
+impl EnvImpl {
+    pub fn new() -> EnvImpl {
+        EnvImpl {
+            buffers: RefCell::new(BufferPool::new())
+        }
+    }
+
+    pub fn release_buffer(&self, ctx: InvokeContext) -> Option<Value> {
+        self.buffers.borrow_mut().release_buffer(ctx)
+    }
+
+    pub fn take_buffer(&self, ctx: InvokeContext) -> Option<Value> {
+        self.buffers.borrow_mut().take_buffer(ctx)
+    }
+
+    pub fn buffer_len(&self, ctx: InvokeContext) -> Option<Value> {
+        self.buffers.borrow().buffer_len(ctx)
+    }
+
+    /// `get_env(name_ptr, name_len)`. Returns the id of a buffer holding the
+    /// value, or `NotFound` if the variable is unset or not in the app's
+    /// `allowed_env` list.
+    pub fn get_env(&self, ctx: InvokeContext) -> Option<Value> {
+        let name = match ctx.try_extract_str(0, 1) {
+            Some(v) => v,
+            None => return Some(ErrorCode::InvalidInput.to_ret())
+        };
+
+        let app = ctx.app.upgrade().unwrap();
+        if !app.with_config(|c| c.allowed_env.iter().any(|v| v == name)) {
+            return Some(ErrorCode::NotFound.to_ret());
+        }
+
+        let value = match ::std::env::var(name) {
+            Ok(v) => v.into_bytes(),
+            Err(_) => return Some(ErrorCode::NotFound.to_ret())
+        };
+        let len = value.len();
+
+        Some(Value::I32(
+            self.buffers.borrow_mut().insert(value.into_boxed_slice(), len) as i32
+        ))
+    }
+}
@@ -1,13 +1,23 @@
+use std::rc::Rc;
 use std::cell::RefCell;
+use std::io;
+use std::path::{Path, PathBuf};
 
 use slab::Slab;
 use std::fs::{File, OpenOptions};
 
 use super::super::namespace::InvokeContext;
 use super::super::error::ErrorCode;
+use super::super::app::ApplicationImpl;
+use super::tcp::BufferPool;
 use wasm_core::value::Value;
 use config::AppPermission;
 
+use futures;
+use futures::Future;
+use tokio;
+use tokio_threadpool;
+
 decl_namespace!(
     FileNs,
     "file",
@@ -17,37 +27,52 @@ decl_namespace!(
     read,
     write,
     flush,
-    seek
+    seek,
+    read_file,
+    write_file,
+    release_buffer,
+    take_buffer,
+    buffer_len
 );
 
+/// Upper bound on a file read by `read_file`.
+const MAX_READ_FILE_SIZE: u64 = 16 * 1024 * 1024;
+
 pub struct FileImpl {
-    handles: RefCell<Slab<File>>
+    handles: RefCell<Slab<File>>,
+    buffers: Rc<RefCell<BufferPool>>,
+    blocking_pool: tokio_threadpool::Sender
 }
 
 impl FileImpl {
-    pub fn new() -> FileImpl {
+    pub fn new(blocking_pool: tokio_threadpool::Sender) -> FileImpl {
         FileImpl {
-            handles: RefCell::new(Slab::new())
+            handles: RefCell::new(Slab::new()),
+            buffers: Rc::new(RefCell::new(BufferPool::new())),
+            blocking_pool: blocking_pool
         }
     }
 
+    pub fn release_buffer(&self, ctx: InvokeContext) -> Option<Value> {
+        self.buffers.borrow_mut().release_buffer(ctx)
+    }
+
+    pub fn take_buffer(&self, ctx: InvokeContext) -> Option<Value> {
+        self.buffers.borrow_mut().take_buffer(ctx)
+    }
+
+    pub fn buffer_len(&self, ctx: InvokeContext) -> Option<Value> {
+        self.buffers.borrow().buffer_len(ctx)
+    }
+
     pub fn open(&self, ctx: InvokeContext) -> Option<Value> {
-        let path = ctx.extract_str(0, 1);
-        let mode = ctx.extract_str(2, 3);
+        let (path, mode) = match (ctx.try_extract_str(0, 1), ctx.try_extract_str(2, 3)) {
+            (Some(p), Some(m)) => (p, m),
+            _ => return Some(ErrorCode::InvalidInput.to_ret())
+        };
         let mut opt = OpenOptions::new();
 
         let app = ctx.app.upgrade().unwrap();
-        match app.check_permission(&AppPermission::FileOpenReadOnlyAny)
-            .or_else(|_| app.check_permission(&AppPermission::FileOpenReadWriteAny)) {
-                Ok(_) => {},
-                Err(_) => {
-                    derror!(
-                        logger!(&app.name),
-                        "FileOpenReadOnlyAny or FileOpenReadWriteAny permissions are required"
-                    );
-                    return Some(ErrorCode::PermissionDenied.to_ret());
-                }
-            }
 
         let mut need_write = false;
 
@@ -80,20 +105,18 @@ impl FileImpl {
             }
         }
 
-        if need_write {
-            match app.check_permission(&AppPermission::FileOpenReadWriteAny) {
-                Ok(_) => {},
-                Err(_) => {
-                    derror!(
-                        logger!(&app.name),
-                        "FileOpenReadWriteAny permission is required"
-                    );
-                    return Some(ErrorCode::PermissionDenied.to_ret());
-                }
+        // Read-write modes need both kinds of access.
+        if need_write && mode.contains('r') {
+            if let Err(code) = check_path_access(&app, path, false) {
+                return Some(code.to_ret());
             }
         }
+        let path = match check_path_access(&app, path, need_write) {
+            Ok(v) => v,
+            Err(code) => return Some(code.to_ret())
+        };
 
-        let f = match opt.open(path) {
+        let f = match opt.open(&path) {
             Ok(v) => v,
             Err(e) => return Some(ErrorCode::from(e.kind()).to_ret())
         };
@@ -105,7 +128,11 @@ impl FileImpl {
 
     pub fn close(&self, ctx: InvokeContext) -> Option<Value> {
         let id = ctx.args[0].get_i32().unwrap() as usize;
-        self.handles.borrow_mut().remove(id);
+
+        let mut handles = self.handles.borrow_mut();
+        if handles.contains(id) {
+            handles.remove(id);
+        }
         None
     }
 
@@ -113,10 +140,16 @@ impl FileImpl {
         use std::io::Read;
 
         let id = ctx.args[0].get_i32().unwrap() as usize;
-        let buf = ctx.extract_bytes_mut(1, 2);
+        let buf = match ctx.try_extract_bytes_mut(1, 2) {
+            Some(v) => v,
+            None => return Some(ErrorCode::InvalidInput.to_ret())
+        };
 
         let mut handles = self.handles.borrow_mut();
-        let file = handles.get_mut(id).unwrap();
+        let file = match handles.get_mut(id) {
+            Some(v) => v,
+            None => return Some(ErrorCode::NotFound.to_ret())
+        };
 
         Some(match file.read(buf) {
             Ok(n) => Value::I32(n as i32),
@@ -128,10 +161,16 @@ impl FileImpl {
         use std::io::Write;
 
         let id = ctx.args[0].get_i32().unwrap() as usize;
-        let buf = ctx.extract_bytes(1, 2);
+        let buf = match ctx.try_extract_bytes(1, 2) {
+            Some(v) => v,
+            None => return Some(ErrorCode::InvalidInput.to_ret())
+        };
 
         let mut handles = self.handles.borrow_mut();
-        let file = handles.get_mut(id).unwrap();
+        let file = match handles.get_mut(id) {
+            Some(v) => v,
+            None => return Some(ErrorCode::NotFound.to_ret())
+        };
 
         Some(match file.write(buf) {
             Ok(n) => Value::I32(n as i32),
@@ -145,7 +184,10 @@ impl FileImpl {
         let id = ctx.args[0].get_i32().unwrap() as usize;
 
         let mut handles = self.handles.borrow_mut();
-        let file = handles.get_mut(id).unwrap();
+        let file = match handles.get_mut(id) {
+            Some(v) => v,
+            None => return Some(ErrorCode::NotFound.to_ret())
+        };
 
         Some(match file.flush() {
             Ok(()) => ErrorCode::Success.to_ret(),
@@ -170,11 +212,329 @@ impl FileImpl {
         };
 
         let mut handles = self.handles.borrow_mut();
-        let file = handles.get_mut(id).unwrap();
+        let file = match handles.get_mut(id) {
+            Some(v) => v,
+            None => return Some(Value::I64(ErrorCode::NotFound.to_i32() as i64))
+        };
 
         Some(Value::I64(match file.seek(from) {
             Ok(v) => v as i64,
             Err(e) => ErrorCode::from(e.kind()).to_i32() as i64
         }))
     }
+
+    /// Reads the whole file at `(path_ptr, path_len)` on a background
+    /// thread. Calls back with `(cb_data, buffer_id)`, or a negative error
+    /// code. Files larger than `MAX_READ_FILE_SIZE` fail with `TooLarge`.
+    pub fn read_file(&self, ctx: InvokeContext) -> Option<Value> {
+        let cb_target = ctx.args[2].get_i32().unwrap();
+        let cb_data = ctx.args[3].get_i32().unwrap();
+
+        let app = ctx.app.upgrade().unwrap();
+
+        let path = match ctx.try_extract_str(0, 1)
+            .ok_or(ErrorCode::InvalidInput)
+            .and_then(|v| check_path_access(&app, v, false)) {
+                Ok(v) => v,
+                Err(code) => {
+                    app.invoke2(cb_target, cb_data, code.to_i32());
+                    return None;
+                }
+            };
+
+        let buffers = self.buffers.clone();
+        let app_weak = ctx.app.clone();
+
+        tokio::executor::current_thread::spawn(
+            run_blocking(&self.blocking_pool, move || read_limited(&path, MAX_READ_FILE_SIZE)).then(move |result| {
+                let ret = match result {
+                    Ok(data) => {
+                        let len = data.len();
+                        buffers.borrow_mut().insert(data.into_boxed_slice(), len) as i32
+                    },
+                    Err(code) => code.to_i32()
+                };
+                if let Some(app) = app_weak.upgrade() {
+                    app.invoke2(
+                        cb_target,
+                        cb_data,
+                        ret
+                    );
+                }
+                Ok(())
+            })
+        );
+
+        None
+    }
+
+    /// Creates or truncates the file at `(path_ptr, path_len)` and writes
+    /// `(data_ptr, data_len)` to it on a background thread. Calls back with
+    /// `(cb_data, n_bytes_written)`, or a negative error code.
+    pub fn write_file(&self, ctx: InvokeContext) -> Option<Value> {
+        let cb_target = ctx.args[4].get_i32().unwrap();
+        let cb_data = ctx.args[5].get_i32().unwrap();
+
+        let app = ctx.app.upgrade().unwrap();
+
+        let checked = ctx.try_extract_str(0, 1)
+            .ok_or(ErrorCode::InvalidInput)
+            .and_then(|v| check_path_access(&app, v, true))
+            .and_then(|path| match ctx.try_extract_bytes(2, 3) {
+                Some(data) => Ok((path, data.to_vec())),
+                None => Err(ErrorCode::InvalidInput)
+            });
+        let (path, data) = match checked {
+            Ok(v) => v,
+            Err(code) => {
+                app.invoke2(cb_target, cb_data, code.to_i32());
+                return None;
+            }
+        };
+
+        let app_weak = ctx.app.clone();
+
+        tokio::executor::current_thread::spawn(
+            run_blocking(&self.blocking_pool, move || {
+                ::std::fs::write(&path, &data)
+                    .map(|_| data.len())
+                    .map_err(|e| ErrorCode::from(e.kind()))
+            }).then(move |result| {
+                let ret = match result {
+                    Ok(n) => n as i32,
+                    Err(code) => code.to_i32()
+                };
+                if let Some(app) = app_weak.upgrade() {
+                    app.invoke2(
+                        cb_target,
+                        cb_data,
+                        ret
+                    );
+                }
+                Ok(())
+            })
+        );
+
+        None
+    }
+}
+
+/// Runs `f` on `pool` so that blocking file I/O doesn't stall the app's
+/// event loop.
+fn run_blocking<T, F>(pool: &tokio_threadpool::Sender, f: F) -> impl Future<Item = T, Error = ErrorCode>
+    where T: Send + 'static, F: FnOnce() -> Result<T, ErrorCode> + Send + 'static
+{
+    let (tx, rx) = futures::sync::oneshot::channel();
+    // If the pool is gone, `tx` is dropped and `rx` fails below.
+    let _ = pool.spawn(futures::future::lazy(move || {
+        let _ = tx.send(f());
+        Ok(())
+    }));
+    rx.then(|result| match result {
+        Ok(v) => v,
+        Err(_) => Err(ErrorCode::Generic)
+    })
+}
+
+/// Reads the file at `path`, failing with `TooLarge` instead of reading
+/// more than `max_len` bytes.
+fn read_limited(path: &Path, max_len: u64) -> Result<Vec<u8>, ErrorCode> {
+    use std::io::Read;
+
+    let file = File::open(path).map_err(|e| ErrorCode::from(e.kind()))?;
+
+    // The file may grow after this check, so the read itself is bounded too.
+    let len = file.metadata().map_err(|e| ErrorCode::from(e.kind()))?.len();
+    if len > max_len {
+        return Err(ErrorCode::TooLarge);
+    }
+
+    let mut data = Vec::with_capacity(len as usize);
+    file.take(max_len + 1).read_to_end(&mut data).map_err(|e| ErrorCode::from(e.kind()))?;
+    if data.len() as u64 > max_len {
+        return Err(ErrorCode::TooLarge);
+    }
+    Ok(data)
+}
+
+/// Checks that `app` may access `path`, for writing if `write` is set.
+///
+/// Apps holding `FileOpenReadOnlyAny`/`FileOpenReadWriteAny` may access any
+/// path. Otherwise the path is canonicalized, which resolves symlinks and
+/// `..`, and must fall under a granted `FsRead`/`FsWrite` prefix. The
+/// canonical path is returned so that the caller opens exactly what was
+/// checked.
+fn check_path_access(app: &ApplicationImpl, path: &str, write: bool) -> Result<PathBuf, ErrorCode> {
+    let mut perms = if write {
+        vec! [ AppPermission::FileOpenReadWriteAny ]
+    } else {
+        vec! [ AppPermission::FileOpenReadOnlyAny, AppPermission::FileOpenReadWriteAny ]
+    };
+    if perms.iter().any(|p| app.check_permission(p).is_ok()) {
+        return Ok(PathBuf::from(path));
+    }
+
+    let canonical = canonicalize_for_access(Path::new(path))
+        .map_err(|e| ErrorCode::from(e.kind()))?;
+    let canonical_str = canonical.to_str().ok_or(ErrorCode::InvalidInput)?.to_string();
+
+    perms.push(if write {
+        AppPermission::FsWrite(canonical_str)
+    } else {
+        AppPermission::FsRead(canonical_str)
+    });
+    app.require_any_permission(&perms)?;
+
+    Ok(canonical)
+}
+
+/// Like `fs::canonicalize`, but also accepts a path to a file that doesn't
+/// exist yet, as long as its parent directory does.
+pub fn canonicalize_for_access(path: &Path) -> io::Result<PathBuf> {
+    match ::std::fs::canonicalize(path) {
+        Ok(v) => Ok(v),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            // A dangling symlink would be followed on creation.
+            if ::std::fs::symlink_metadata(path).is_ok() {
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, "dangling symlink"));
+            }
+
+            let name = match path.file_name() {
+                Some(v) => v,
+                None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "no file name"))
+            };
+            let parent = match path.parent() {
+                Some(v) if !v.as_os_str().is_empty() => v,
+                _ => Path::new(".")
+            };
+            Ok(::std::fs::canonicalize(parent)?.join(name))
+        },
+        Err(e) => Err(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::symlink;
+    use std::path::PathBuf;
+    use wasm_core::value::Value;
+    use config::AppPermission;
+    use container::Container;
+    use super::super::super::app::Application;
+    use super::super::super::error::ErrorCode;
+    use super::super::super::testutil::*;
+    use super::{FileImpl, check_path_access, read_limited, MAX_READ_FILE_SIZE};
+
+    /// Creates `granted/file`, `outside/secret` and symlinks from
+    /// `granted` to the latter. Returns the canonical base directory.
+    fn fs_tree(name: &str) -> PathBuf {
+        let dir = ::std::env::temp_dir().join(format!(
+            "ice-file-test-{}-{}",
+            ::std::process::id(),
+            name
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("granted")).unwrap();
+        fs::create_dir_all(dir.join("outside")).unwrap();
+        let dir = fs::canonicalize(&dir).unwrap();
+
+        fs::write(dir.join("granted/file"), b"ok").unwrap();
+        fs::write(dir.join("outside/secret"), b"secret").unwrap();
+        symlink(dir.join("outside/secret"), dir.join("granted/escape")).unwrap();
+        symlink(dir.join("outside/missing"), dir.join("granted/dangling")).unwrap();
+        dir
+    }
+
+    fn fs_app(dir: &PathBuf) -> (Container, Application) {
+        let granted = dir.join("granted").to_str().unwrap().to_string();
+        idle_app("file-access", &[
+            AppPermission::FsRead(granted.clone()),
+            AppPermission::FsWrite(granted)
+        ])
+    }
+
+    #[test]
+    fn access_is_limited_to_granted_prefix() {
+        let dir = fs_tree("prefix");
+        let (_container, app) = fs_app(&dir);
+        let path = |p: &str| dir.join(p).to_str().unwrap().to_string();
+
+        assert_eq!(check_path_access(&app, &path("granted/file"), false), Ok(dir.join("granted/file")));
+        assert_eq!(check_path_access(&app, &path("outside/secret"), false), Err(ErrorCode::PermissionDenied));
+
+        // Symlinks are resolved before the prefix check.
+        assert_eq!(check_path_access(&app, &path("granted/escape"), false), Err(ErrorCode::PermissionDenied));
+
+        // So is `..`.
+        assert_eq!(check_path_access(&app, &path("granted/../outside/secret"), false), Err(ErrorCode::PermissionDenied));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn new_files_are_checked_against_their_parent() {
+        let dir = fs_tree("new");
+        let (_container, app) = fs_app(&dir);
+        let path = |p: &str| dir.join(p).to_str().unwrap().to_string();
+
+        assert_eq!(check_path_access(&app, &path("granted/new"), true), Ok(dir.join("granted/new")));
+        assert_eq!(check_path_access(&app, &path("outside/new"), true), Err(ErrorCode::PermissionDenied));
+
+        // Creating through a dangling symlink would write outside the
+        // prefix.
+        assert_eq!(check_path_access(&app, &path("granted/dangling"), true), Err(ErrorCode::PermissionDenied));
+
+        // The parent must exist.
+        assert_eq!(check_path_access(&app, &path("granted/missing/new"), true), Err(ErrorCode::NotFound));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn read_limited_enforces_max_size() {
+        let dir = fs_tree("limit");
+        let path = dir.join("granted/big");
+
+        let f = fs::File::create(&path).unwrap();
+        f.set_len(MAX_READ_FILE_SIZE).unwrap();
+        assert_eq!(read_limited(&path, MAX_READ_FILE_SIZE).unwrap().len() as u64, MAX_READ_FILE_SIZE);
+
+        f.set_len(MAX_READ_FILE_SIZE + 1).unwrap();
+        assert_eq!(read_limited(&path, MAX_READ_FILE_SIZE), Err(ErrorCode::TooLarge));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn open_rejects_out_of_bounds_arguments() {
+        let dir = fs_tree("open");
+        let (container, app) = fs_app(&dir);
+        let file = FileImpl::new(container.blocking_pool());
+
+        let path = dir.join("granted/file");
+        let path = path.to_str().unwrap().as_bytes();
+        let mut state = TestState::new(path.len() + 1);
+        state.memory[..path.len()].copy_from_slice(path);
+        state.memory[path.len()] = b'r';
+
+        let args = |path_len: i32, mode_len: i32| [
+            Value::I32(0),
+            Value::I32(path_len),
+            Value::I32(path.len() as i32),
+            Value::I32(mode_len)
+        ];
+        assert_eq!(
+            call(&app, &mut state, &args(path.len() as i32 + 100, 1), |ctx| file.open(ctx)).unwrap().get_i32().unwrap(),
+            ErrorCode::InvalidInput.to_i32()
+        );
+        assert_eq!(
+            call(&app, &mut state, &args(path.len() as i32, 2), |ctx| file.open(ctx)).unwrap().get_i32().unwrap(),
+            ErrorCode::InvalidInput.to_i32()
+        );
+        assert!(call(&app, &mut state, &args(path.len() as i32, 1), |ctx| file.open(ctx)).unwrap().get_i32().unwrap() >= 0);
+        assert!(!app.is_faulted());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,333 @@
+use config::AppPermission;
+use super::super::namespace::InvokeContext;
+use super::super::error::ErrorCode;
+use super::tcp::BufferPool;
+use wasm_core::value::Value;
+use std::rc::Rc;
+use std::cell::RefCell;
+use slab::Slab;
+
+use futures::Future;
+use futures::Stream;
+use tokio;
+use hyper;
+use hyper::{Body, Client, Method, Request, Uri};
+use hyper::client::HttpConnector;
+
+decl_namespace!(
+    HttpClientNs,
+    "httpc",
+    HttpClientImpl,
+    release_buffer,
+    take_buffer,
+    buffer_len,
+    request,
+    status,
+    headers,
+    read_body,
+    destroy
+);
+
+/// Upper bound on a response body read by `read_body`.
+const MAX_BODY_SIZE: usize = 16 * 1024 * 1024;
+
+pub struct HttpClientImpl {
+    client: Client<HttpConnector>,
+    responses: Rc<RefCell<Slab<ResponseEntry>>>,
+    buffers: Rc<RefCell<BufferPool>>
+}
+
+struct ResponseEntry {
+    status: u16,
+    headers: hyper::HeaderMap,
+    // Taken by `read_body`.
+    body: Option<Body>
+}
+
+impl HttpClientImpl {
+    pub fn new() -> HttpClientImpl {
+        HttpClientImpl {
+            client: Client::builder().build(HttpConnector::new(1)),
+            responses: Rc::new(RefCell::new(Slab::new())),
+            buffers: Rc::new(RefCell::new(BufferPool::new()))
+        }
+    }
+
+    pub fn release_buffer(&self, ctx: InvokeContext) -> Option<Value> {
+        self.buffers.borrow_mut().release_buffer(ctx)
+    }
+
+    pub fn take_buffer(&self, ctx: InvokeContext) -> Option<Value> {
+        self.buffers.borrow_mut().take_buffer(ctx)
+    }
+
+    pub fn buffer_len(&self, ctx: InvokeContext) -> Option<Value> {
+        self.buffers.borrow().buffer_len(ctx)
+    }
+
+    /// Arguments: `(method, url, headers, body, cb_target, cb_data)`, where
+    /// each of the first four is a `(ptr, len)` pair and `headers` holds
+    /// `Name: value` lines.
+    ///
+    /// Calls back with `(cb_data, response_id)`, or a negative error code.
+    /// Only `http://` URLs are supported.
+    pub fn request(&self, ctx: InvokeContext) -> Option<Value> {
+        let cb_target = ctx.args[8].get_i32().unwrap();
+        let cb_data = ctx.args[9].get_i32().unwrap();
+
+        let app = ctx.app.upgrade().unwrap();
+
+        let req = match build_request(&ctx) {
+            Some(v) => v,
+            None => {
+                derror!(logger!(&app.name), "Invalid HTTP request");
+                app.invoke2(
+                    cb_target,
+                    cb_data,
+                    ErrorCode::InvalidInput.to_i32()
+                );
+                return None;
+            }
+        };
+
+        let host = req.uri().host().unwrap().to_string();
+        if let Err(code) = app.require_any_permission(&[
+            AppPermission::HttpRequest(host)
+        ]) {
+            app.invoke2(
+                cb_target,
+                cb_data,
+                code.to_i32()
+            );
+            return None;
+        }
+
+        let responses = self.responses.clone();
+        let app_weak = ctx.app.clone();
+
+        tokio::executor::current_thread::spawn(
+            self.client.request(req).then(move |result| {
+                let ret = match result {
+                    Ok(resp) => {
+                        let (parts, body) = resp.into_parts();
+                        responses.borrow_mut().insert(ResponseEntry {
+                            status: parts.status.as_u16(),
+                            headers: parts.headers,
+                            body: Some(body)
+                        }) as i32
+                    },
+                    Err(e) => {
+                        derror!(logger!("(app)"), "HTTP request error: {:?}", e);
+                        ErrorCode::Generic.to_i32()
+                    }
+                };
+                if let Some(app) = app_weak.upgrade() {
+                    app.invoke2(
+                        cb_target,
+                        cb_data,
+                        ret
+                    );
+                }
+                Ok(())
+            })
+        );
+
+        None
+    }
+
+    pub fn status(&self, ctx: InvokeContext) -> Option<Value> {
+        let resp_id = ctx.args[0].get_i32().unwrap() as usize;
+
+        match self.responses.borrow().get(resp_id) {
+            Some(v) => Some(Value::I32(v.status as i32)),
+            None => Some(ErrorCode::NotFound.to_ret())
+        }
+    }
+
+    /// Returns the id of a buffer holding the response headers as
+    /// `Name: value` lines.
+    pub fn headers(&self, ctx: InvokeContext) -> Option<Value> {
+        let resp_id = ctx.args[0].get_i32().unwrap() as usize;
+
+        let responses = self.responses.borrow();
+        let entry = match responses.get(resp_id) {
+            Some(v) => v,
+            None => return Some(ErrorCode::NotFound.to_ret())
+        };
+
+        let mut out: Vec<u8> = Vec::new();
+        for (k, v) in entry.headers.iter() {
+            out.extend_from_slice(k.as_str().as_bytes());
+            out.extend_from_slice(b": ");
+            out.extend_from_slice(v.as_bytes());
+            out.push(b'\n');
+        }
+        let len = out.len();
+
+        Some(Value::I32(
+            self.buffers.borrow_mut().insert(out.into_boxed_slice(), len) as i32
+        ))
+    }
+
+    /// Reads the whole response body. Calls back with `(cb_data, buffer_id)`,
+    /// or a negative error code. The body can only be read once.
+    ///
+    /// Bodies larger than `MAX_BODY_SIZE` fail with `TooLarge`.
+    pub fn read_body(&self, ctx: InvokeContext) -> Option<Value> {
+        let resp_id = ctx.args[0].get_i32().unwrap() as usize;
+        let cb_target = ctx.args[1].get_i32().unwrap();
+        let cb_data = ctx.args[2].get_i32().unwrap();
+
+        let body = match self.responses.borrow_mut().get_mut(resp_id) {
+            Some(v) => v.body.take().ok_or(ErrorCode::Eof),
+            None => Err(ErrorCode::NotFound)
+        };
+        let body = match body {
+            Ok(v) => v,
+            Err(code) => {
+                ctx.app.upgrade().unwrap().invoke2(
+                    cb_target,
+                    cb_data,
+                    code.to_i32()
+                );
+                return None;
+            }
+        };
+
+        let buffers = self.buffers.clone();
+        let app_weak = ctx.app.clone();
+
+        tokio::executor::current_thread::spawn(
+            body.map_err(|e| {
+                derror!(logger!("(app)"), "HTTP body error: {:?}", e);
+                ErrorCode::Generic
+            }).fold(Vec::new(), |mut data, chunk| {
+                if data.len() + chunk.len() > MAX_BODY_SIZE {
+                    return Err(ErrorCode::TooLarge);
+                }
+                data.extend_from_slice(&chunk);
+                Ok(data)
+            }).then(move |result| {
+                let ret = match result {
+                    Ok(data) => {
+                        let len = data.len();
+                        buffers.borrow_mut().insert(data.into_boxed_slice(), len) as i32
+                    },
+                    Err(code) => code.to_i32()
+                };
+                if let Some(app) = app_weak.upgrade() {
+                    app.invoke2(
+                        cb_target,
+                        cb_data,
+                        ret
+                    );
+                }
+                Ok(())
+            })
+        );
+
+        None
+    }
+
+    pub fn destroy(&self, ctx: InvokeContext) -> Option<Value> {
+        let resp_id = ctx.args[0].get_i32().unwrap() as usize;
+
+        let mut responses = self.responses.borrow_mut();
+        if !responses.contains(resp_id) {
+            return Some(ErrorCode::NotFound.to_ret());
+        }
+        responses.remove(resp_id);
+
+        Some(ErrorCode::Success.to_ret())
+    }
+}
+
+fn build_request(ctx: &InvokeContext) -> Option<Request<Body>> {
+    let method: Method = ctx.try_extract_str(0, 1)?.parse().ok()?;
+    let uri: Uri = ctx.try_extract_str(2, 3)?.parse().ok()?;
+    let headers = ctx.try_extract_str(4, 5)?;
+    let body = ctx.try_extract_bytes(6, 7)?.to_vec();
+
+    if uri.scheme_part().map(|v| v.as_str()) != Some("http") || uri.host().is_none() {
+        return None;
+    }
+
+    let mut builder = Request::builder();
+    builder.method(method).uri(uri);
+
+    for line in headers.lines() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut parts = line.splitn(2, ':');
+        let name = parts.next()?.trim();
+        let value = parts.next()?.trim();
+        builder.header(name, value);
+    }
+
+    builder.body(Body::from(body)).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::{Body, Request};
+    use wasm_core::value::Value;
+    use super::super::super::testutil::*;
+    use super::build_request;
+
+    /// Lays out the request arguments in app memory and builds the request.
+    fn request(method: &str, uri: &str, headers: &str) -> Option<Request<Body>> {
+        let (_container, app) = idle_app("httpc-build", &[]);
+        let mut state = TestState::new(0);
+
+        let mut args = Vec::new();
+        for part in &[method.as_bytes(), uri.as_bytes(), headers.as_bytes(), &b"body"[..]] {
+            args.push(Value::I32(state.memory.len() as i32));
+            args.push(Value::I32(part.len() as i32));
+            state.memory.extend_from_slice(part);
+        }
+
+        let mut req = None;
+        call(&app, &mut state, &args, |ctx| {
+            req = build_request(&ctx);
+            None
+        });
+        req
+    }
+
+    #[test]
+    fn builds_valid_request() {
+        let req = request("POST", "http://example.com/a?b=c", "X-Test: 1\r\nAccept: */*\r\n").unwrap();
+        assert_eq!(req.method(), "POST");
+        assert_eq!(req.uri(), "http://example.com/a?b=c");
+        assert_eq!(req.headers()["x-test"], "1");
+        assert_eq!(req.headers()["accept"], "*/*");
+    }
+
+    #[test]
+    fn rejects_invalid_method() {
+        assert!(request("GE T", "http://example.com/", "").is_none());
+        assert!(request("", "http://example.com/", "").is_none());
+    }
+
+    #[test]
+    fn rejects_malformed_url() {
+        assert!(request("GET", "http://exa mple.com/", "").is_none());
+        assert!(request("GET", "/relative/path", "").is_none());
+        assert!(request("GET", "http:///no-host", "").is_none());
+    }
+
+    #[test]
+    fn rejects_invalid_header_bytes() {
+        assert!(request("GET", "http://example.com/", "Bad Name: 1").is_none());
+        assert!(request("GET", "http://example.com/", "X-Test: a\x01b").is_none());
+        assert!(request("GET", "http://example.com/", "no-colon").is_none());
+    }
+
+    #[test]
+    fn rejects_unsupported_scheme() {
+        assert!(request("GET", "ftp://example.com/file", "").is_none());
+        // There is no TLS connector.
+        assert!(request("GET", "https://example.com/", "").is_none());
+    }
+}
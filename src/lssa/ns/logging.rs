@@ -1,4 +1,5 @@
 use super::super::namespace::InvokeContext;
+use super::super::error::ErrorCode;
 use wasm_core::value::Value;
 
 decl_namespace!(
@@ -6,7 +7,8 @@ decl_namespace!(
     "logging",
     LoggingImpl,
     info,
-    warning
+    warning,
+    log
 );
 
 pub struct LoggingImpl;
@@ -27,4 +29,26 @@ impl LoggingImpl {
         dwarning!(logger!(&app.name), "{}", text);
         None
     }
+
+    /// `log(level, ptr, len)`, where `level` is one of
+    /// 0 (error), 1 (warning), 2 (info) and 3 (debug).
+    pub fn log(&self, ctx: InvokeContext) -> Option<Value> {
+        let level = ctx.args[0].get_i32().unwrap();
+        let text = match ctx.try_extract_str(1, 2) {
+            Some(v) => v,
+            None => return Some(ErrorCode::InvalidInput.to_ret())
+        };
+        let app = ctx.app.upgrade().unwrap();
+        let logger = logger!(&app.name);
+
+        match level {
+            0 => derror!(logger, "{}", text),
+            1 => dwarning!(logger, "{}", text),
+            2 => dinfo!(logger, "{}", text),
+            3 => ddebug!(logger, "{}", text),
+            _ => return Some(ErrorCode::InvalidInput.to_ret())
+        }
+
+        Some(ErrorCode::Success.to_ret())
+    }
 }
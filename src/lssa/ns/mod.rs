@@ -2,3 +2,10 @@ pub mod timer;
 pub mod logging;
 pub mod tcp;
 pub mod file;
+pub mod udp;
+pub mod http_client;
+pub mod random;
+pub mod env;
+pub mod msg;
+#[cfg(unix)]
+pub mod uds;
@@ -0,0 +1,238 @@
+use config::AppPermission;
+use super::super::namespace::InvokeContext;
+use super::super::error::ErrorCode;
+use super::super::app::{Application, ApplicationImpl};
+use super::super::control::Control;
+use super::super::event::{Event, EventInfo};
+use super::tcp::BufferPool;
+use wasm_core::value::Value;
+use std::cell::{Cell, RefCell};
+
+use futures;
+use futures::Future;
+use tokio;
+
+decl_namespace!(
+    MsgNs,
+    "msg",
+    MsgImpl,
+    send,
+    set_receiver,
+    release_buffer,
+    take_buffer,
+    buffer_len
+);
+
+/// App-to-app messaging. Messages are routed through the app manager as
+/// events, so delivery is always asynchronous.
+pub struct MsgImpl;
+
+/// The receiving end of an app's messages. Lives on the app itself, since
+/// senders only know the target app, not its namespaces.
+pub struct Mailbox {
+    receiver: Cell<Option<(i32, i32)>>,
+    buffers: RefCell<BufferPool>
+}
+
+impl Mailbox {
+    pub fn new() -> Mailbox {
+        Mailbox {
+            receiver: Cell::new(None),
+            buffers: RefCell::new(BufferPool::new())
+        }
+    }
+
+    /// Hands `payload` to the receiver. Fails with `Generic` if the app is
+    /// faulted or the receiver traps.
+    fn deliver(&self, app: &ApplicationImpl, from: &str, payload: &[u8]) -> Result<(), ErrorCode> {
+        let (cb_target, cb_data) = match self.receiver.get() {
+            Some(v) => v,
+            None => return Err(ErrorCode::NotFound)
+        };
+
+        if app.is_faulted() {
+            return Err(ErrorCode::Generic);
+        }
+
+        let (payload_id, from_id) = {
+            let mut buffers = self.buffers.borrow_mut();
+            (
+                buffers.insert(payload.to_vec().into_boxed_slice(), payload.len()),
+                buffers.insert(from.as_bytes().to_vec().into_boxed_slice(), from.len())
+            )
+        };
+
+        let ret = app.invoke3(
+            cb_target,
+            cb_data,
+            payload_id as i32,
+            from_id as i32
+        );
+        if ret < 0 || app.is_faulted() {
+            // The receiver may have taken either buffer before failing.
+            let mut buffers = self.buffers.borrow_mut();
+            buffers.release(payload_id);
+            buffers.release(from_id);
+            return Err(ErrorCode::Generic);
+        }
+        Ok(())
+    }
+}
+
+struct MessageEvent {
+    from: String,
+    payload: Vec<u8>,
+    // Dropped without a value if the target app is gone, which the sender
+    // sees as `NotFound`.
+    result: Cell<Option<futures::sync::oneshot::Sender<Result<(), ErrorCode>>>>
+}
+
+impl Event for MessageEvent {
+    fn notify(&self, app: &Application) {
+        let result = app.mailbox.deliver(app, &self.from, &self.payload);
+        if let Some(tx) = self.result.take() {
+            let _ = tx.send(result);
+        }
+    }
+}
+
+impl MsgImpl {
+    /// `send(target_ptr, target_len, payload_ptr, payload_len, cb_target, cb_data)`
+    ///
+    /// Sends `payload` to the app named `target`, which must have registered
+    /// a receiver. Calls back with `(cb_data, ret)` once the message has
+    /// been handed to the receiver, with `NotFound` if the target isn't
+    /// running or has no receiver, or with `Generic` if the target is
+    /// faulted or its receiver traps.
+    pub fn send(&self, ctx: InvokeContext) -> Option<Value> {
+        let cb_target = ctx.args[4].get_i32().unwrap();
+        let cb_data = ctx.args[5].get_i32().unwrap();
+
+        let app = ctx.app.upgrade().unwrap();
+
+        let (target, payload) = match (ctx.try_extract_str(0, 1), ctx.try_extract_bytes(2, 3)) {
+            (Some(t), Some(p)) => (t, p),
+            _ => {
+                app.invoke2(
+                    cb_target,
+                    cb_data,
+                    ErrorCode::InvalidInput.to_i32()
+                );
+                return None;
+            }
+        };
+
+        if let Err(code) = app.require_any_permission(&[
+            AppPermission::MessageApp(target.to_string())
+        ]) {
+            app.invoke2(
+                cb_target,
+                cb_data,
+                code.to_i32()
+            );
+            return None;
+        }
+
+        let (tx, rx) = futures::sync::oneshot::channel();
+        let dispatched = match app.container.lookup_app_id_by_name(target) {
+            Some(target_id) => app.container.dispatch_control(Control::Event(EventInfo::new(
+                target_id,
+                MessageEvent {
+                    from: app.name.clone(),
+                    payload: payload.to_vec(),
+                    result: Cell::new(Some(tx))
+                }
+            ))).map_err(|_| ErrorCode::Generic),
+            None => Err(ErrorCode::NotFound)
+        };
+        if let Err(code) = dispatched {
+            app.invoke2(
+                cb_target,
+                cb_data,
+                code.to_i32()
+            );
+            return None;
+        }
+
+        let app_weak = ctx.app.clone();
+
+        tokio::executor::current_thread::spawn(rx.then(move |result| {
+            let ret = match result {
+                Ok(Ok(())) => ErrorCode::Success.to_i32(),
+                Ok(Err(code)) => code.to_i32(),
+                Err(_) => ErrorCode::NotFound.to_i32()
+            };
+            if let Some(app) = app_weak.upgrade() {
+                app.invoke2(
+                    cb_target,
+                    cb_data,
+                    ret
+                );
+            }
+            Ok(())
+        }));
+
+        None
+    }
+
+    /// `set_receiver(cb_target, cb_data)`
+    ///
+    /// Registers the callback for incoming messages, replacing any previous
+    /// one. It is called with `(cb_data, payload_buffer_id, sender_buffer_id)`,
+    /// where the second buffer holds the sender's app name.
+    pub fn set_receiver(&self, ctx: InvokeContext) -> Option<Value> {
+        let cb_target = ctx.args[0].get_i32().unwrap();
+        let cb_data = ctx.args[1].get_i32().unwrap();
+
+        let app = ctx.app.upgrade().unwrap();
+        app.mailbox.receiver.set(Some((cb_target, cb_data)));
+
+        None
+    }
+
+    pub fn release_buffer(&self, ctx: InvokeContext) -> Option<Value> {
+        let app = ctx.app.upgrade().unwrap();
+        let ret = app.mailbox.buffers.borrow_mut().release_buffer(ctx);
+        ret
+    }
+
+    pub fn take_buffer(&self, ctx: InvokeContext) -> Option<Value> {
+        let app = ctx.app.upgrade().unwrap();
+        let ret = app.mailbox.buffers.borrow_mut().take_buffer(ctx);
+        ret
+    }
+
+    pub fn buffer_len(&self, ctx: InvokeContext) -> Option<Value> {
+        let app = ctx.app.upgrade().unwrap();
+        let ret = app.mailbox.buffers.borrow().buffer_len(ctx);
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use container::Container;
+    use super::super::super::error::ErrorCode;
+    use super::super::super::testutil::*;
+
+    #[test]
+    fn delivery_reports_trapping_receiver() {
+        let container = Container::new(config_with_apps(&["ok", "trap"]));
+
+        let app = load_app(&container, "ok", &app_module(RETURN_ZERO, RETURN_ZERO));
+        app.initialize(None).unwrap();
+        assert_eq!(app.mailbox.deliver(&app, "sender", b"hi"), Err(ErrorCode::NotFound));
+        app.mailbox.receiver.set(Some((0, 0)));
+        assert_eq!(app.mailbox.deliver(&app, "sender", b"hi"), Ok(()));
+
+        let app = load_app(&container, "trap", &app_module(RETURN_ZERO, TRAP));
+        app.initialize(None).unwrap();
+        app.mailbox.receiver.set(Some((0, 0)));
+        assert_eq!(app.mailbox.deliver(&app, "sender", b"hi"), Err(ErrorCode::Generic));
+        assert!(app.is_faulted());
+        assert!(app.mailbox.buffers.borrow().is_empty());
+
+        // Later messages are refused without calling into the app.
+        assert_eq!(app.mailbox.deliver(&app, "sender", b"hi"), Err(ErrorCode::Generic));
+    }
+}
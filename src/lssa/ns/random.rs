@@ -0,0 +1,40 @@
+use super::super::namespace::InvokeContext;
+use super::super::error::ErrorCode;
+use wasm_core::value::Value;
+
+use rand::RngCore;
+use rand::rngs::OsRng;
+
+decl_namespace!(
+    RandomNs,
+    "random",
+    RandomImpl,
+    fill_random
+);
+
+pub struct RandomImpl;
+
+impl RandomImpl {
+    /// Fills `(ptr, len)` with bytes from the OS CSPRNG.
+    pub fn fill_random(&self, mut ctx: InvokeContext) -> Option<Value> {
+        let target = match ctx.try_extract_bytes_mut(0, 1) {
+            Some(v) => v,
+            None => return Some(ErrorCode::InvalidInput.to_ret())
+        };
+
+        let mut rng = match OsRng::new() {
+            Ok(v) => v,
+            Err(e) => {
+                derror!(logger!("(app)"), "Unable to open OS RNG: {:?}", e);
+                return Some(ErrorCode::Generic.to_ret());
+            }
+        };
+        match rng.try_fill_bytes(target) {
+            Ok(_) => Some(ErrorCode::Success.to_ret()),
+            Err(e) => {
+                derror!(logger!("(app)"), "Unable to read OS RNG: {:?}", e);
+                Some(ErrorCode::Generic.to_ret())
+            }
+        }
+    }
+}
@@ -1,17 +1,22 @@
 use config::AppPermission;
 use super::super::namespace::InvokeContext;
+use super::super::app::ApplicationImpl;
 use wasm_core::value::Value;
 use std::net::SocketAddr;
-use std::rc::Rc;
-use std::cell::RefCell;
+use std::rc::{Rc, Weak};
+use std::cell::{Cell, RefCell};
+use std::io;
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
 use slab::Slab;
 
 use futures;
 use futures::{Future, Stream};
+use futures::future::Either;
 use tokio;
-use tokio::prelude::AsyncRead;
-use tokio_io::io::{ReadHalf, WriteHalf};
+use tokio::prelude::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
+use net2;
 use super::super::error::ErrorCode;
 
 decl_namespace!(
@@ -20,56 +25,156 @@ decl_namespace!(
     TcpImpl,
     release_buffer,
     take_buffer,
+    buffer_len,
     connect,
     listen,
+    stop_listen,
+    listener_addr,
     read,
     write,
-    destroy
+    destroy,
+    peer_addr,
+    set_nodelay,
+    set_read_timeout,
+    set_idle_timeout,
+    shutdown
 );
 
 pub struct TcpImpl {
-    streams: Rc<RefCell<Slab<(
-        Option<ReadHalf<TcpStream>>,
-        Option<WriteHalf<TcpStream>>
-    )>>>,
-    buffers: Rc<RefCell<Slab<Box<[u8]>>>>
+    streams: Rc<RefCell<Slab<StreamEntry>>>,
+    buffers: Rc<RefCell<BufferPool>>,
+    listeners: RefCell<Slab<ListenerHandle>>,
+    idle_timeout: Rc<Cell<Option<IdleTimeout>>>,
+    reaper_running: Rc<Cell<bool>>
+}
+
+#[derive(Copy, Clone)]
+struct IdleTimeout {
+    timeout: Duration,
+    cb_target: i32,
+    cb_data: i32
+}
+
+/// How often accepted streams are checked against the idle timeout.
+const IDLE_SWEEP_INTERVAL_MS: u64 = 1000;
+
+struct StreamEntry {
+    stream: SharedStream,
+    // Set while a read/write on this stream is pending.
+    reading: bool,
+    writing: bool,
+    read_timeout: Option<Duration>,
+    // Dropped along with the entry on `destroy`, which cancels the
+    // pending read/write.
+    read_cancel: Option<futures::sync::oneshot::Sender<()>>,
+    write_cancel: Option<futures::sync::oneshot::Sender<()>>,
+    // Idle tracking, for accepted streams only.
+    accepted: bool,
+    last_activity: Instant,
+    idle_closed: bool,
+    _conn_guard: Option<ConnectionGuard>
+}
+
+impl StreamEntry {
+    fn new(stream: TcpStream) -> StreamEntry {
+        StreamEntry {
+            stream: SharedStream(Rc::new(stream)),
+            reading: false,
+            writing: false,
+            read_timeout: None,
+            read_cancel: None,
+            write_cancel: None,
+            accepted: false,
+            last_activity: Instant::now(),
+            idle_closed: false,
+            _conn_guard: None
+        }
+    }
+}
+
+/// Returns the entry at `stream_id` only if it still holds `stream`. After a
+/// `destroy`, the id may already belong to another stream.
+fn entry_for<'a>(
+    streams: &'a mut Slab<StreamEntry>,
+    stream_id: usize,
+    stream: &SharedStream
+) -> Option<&'a mut StreamEntry> {
+    streams.get_mut(stream_id).filter(|e| Rc::ptr_eq(&e.stream.0, &stream.0))
+}
+
+/// Counts a stream accepted by a listener against the listener's
+/// connection limit for as long as the stream is in the slab.
+struct ConnectionGuard(Rc<Cell<usize>>);
+
+impl ConnectionGuard {
+    fn new(count: Rc<Cell<usize>>) -> ConnectionGuard {
+        count.set(count.get() + 1);
+        ConnectionGuard(count)
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.set(self.0.get() - 1);
+    }
+}
+
+/// A handle to a `TcpStream` that can be moved into a pending read or
+/// write while the slab keeps access to the socket itself.
+#[derive(Clone)]
+struct SharedStream(Rc<TcpStream>);
+
+impl Read for SharedStream {
+    fn read(&mut self, buf: &mut [u8]) -> ::std::io::Result<usize> {
+        (&*self.0).read(buf)
+    }
+}
+
+impl AsyncRead for SharedStream {}
+
+impl Write for SharedStream {
+    fn write(&mut self, buf: &[u8]) -> ::std::io::Result<usize> {
+        (&*self.0).write(buf)
+    }
+
+    fn flush(&mut self) -> ::std::io::Result<()> {
+        (&*self.0).flush()
+    }
+}
+
+impl AsyncWrite for SharedStream {
+    fn shutdown(&mut self) -> futures::Poll<(), ::std::io::Error> {
+        AsyncWrite::shutdown(&mut &*self.0)
+    }
+}
+
+struct ListenerHandle {
+    local_addr: Option<SocketAddr>,
+    // Dropping this sender terminates the accept loop.
+    _stop: futures::sync::oneshot::Sender<()>
 }
 
 impl TcpImpl {
     pub fn new() -> TcpImpl {
         TcpImpl {
             streams: Rc::new(RefCell::new(Slab::new())),
-            buffers: Rc::new(RefCell::new(Slab::new()))
+            buffers: Rc::new(RefCell::new(BufferPool::new())),
+            listeners: RefCell::new(Slab::new()),
+            idle_timeout: Rc::new(Cell::new(None)),
+            reaper_running: Rc::new(Cell::new(false))
         }
     }
 
     pub fn connect(&self, ctx: InvokeContext) -> Option<Value> {
-        let addr = ctx.extract_str(0, 1);
         let cb_target = ctx.args[2].get_i32().unwrap();
         let cb_data = ctx.args[3].get_i32().unwrap();
 
         let app = ctx.app.upgrade().unwrap();
-        match app.check_permission(&AppPermission::TcpConnectAny)
-            .or_else(|_| app.check_permission(&AppPermission::TcpConnect(addr.to_string()))) {
-                Ok(_) => {},
-                Err(_) => {
-                    derror!(
-                        logger!(&app.name),
-                        "TcpConnectAny or TcpConnect({}) permission is required",
-                        addr
-                    );
-                    app.invoke2(
-                        cb_target,
-                        cb_data,
-                        ErrorCode::PermissionDenied.to_i32()
-                    );
-                    return None;
-                }
-            }
-
-        let saddr: SocketAddr = match addr.parse() {
-            Ok(v) => v,
-            Err(_) => {
+        let parsed = ctx.try_extract_str(0, 1)
+            .and_then(|addr| addr.parse::<SocketAddr>().ok().map(|saddr| (addr, saddr)));
+        let (addr, saddr) = match parsed {
+            Some(v) => v,
+            None => {
                 app.invoke2(
                     cb_target,
                     cb_data,
@@ -78,6 +183,18 @@ impl TcpImpl {
                 return None;
             }
         };
+        if let Err(code) = app.require_any_permission(&[
+            AppPermission::TcpConnectAny,
+            AppPermission::TcpConnect(addr.to_string())
+        ]) {
+            app.invoke2(
+                cb_target,
+                cb_data,
+                code.to_i32()
+            );
+            return None;
+        }
+
         let streams = self.streams.clone();
         let app_weak1 = ctx.app.clone();
         let app_weak2 = ctx.app.clone();
@@ -85,24 +202,26 @@ impl TcpImpl {
         tokio::executor::current_thread::spawn(
             tokio::net::TcpStream::connect(&saddr)
                 .map(move |stream| {
-                    let (rh, wh) = stream.split();
-                    let stream_id = streams.borrow_mut().insert((
-                        Some(rh),
-                        Some(wh)
-                    ));
-                    app_weak1.upgrade().unwrap().invoke2(
-                        cb_target,
-                        cb_data,
-                        stream_id as _
+                    let stream_id = streams.borrow_mut().insert(
+                        StreamEntry::new(stream)
                     );
+                    if let Some(app) = app_weak1.upgrade() {
+                        app.invoke2(
+                            cb_target,
+                            cb_data,
+                            stream_id as _
+                        );
+                    }
                 })
                 .or_else(move |e| {
                     derror!(logger!("(app)"), "Connect error: {:?}", e);
-                    app_weak2.upgrade().unwrap().invoke2(
-                        cb_target,
-                        cb_data,
-                        -1
-                    );
+                    if let Some(app) = app_weak2.upgrade() {
+                        app.invoke2(
+                            cb_target,
+                            cb_data,
+                            ErrorCode::from(e.kind()).to_i32()
+                        );
+                    }
                     Ok(())
                 })
         );
@@ -110,38 +229,58 @@ impl TcpImpl {
         None
     }
 
+    /// `listen(addr, cb_target, cb_data, max_conns, flags)`
+    ///
+    /// `addr` is `ip:port`, with IPv6 addresses in brackets (`[::1]:8080`).
+    /// If `flags` contains `LISTEN_DUAL_STACK`, an IPv6 listener also
+    /// accepts IPv4 connections; this has no effect on IPv4 addresses.
+    ///
+    /// Accepted streams count against `max_conns` until they are
+    /// `destroy`ed, including streams whose reads/writes have failed.
+    /// Incoming connections over the limit are closed immediately.
+    /// `max_conns <= 0` means no limit.
     pub fn listen(&self, ctx: InvokeContext) -> Option<Value> {
-        let addr = ctx.extract_str(0, 1);
+        let addr = match ctx.try_extract_str(0, 1) {
+            Some(v) => v,
+            None => return Some(ErrorCode::InvalidInput.to_ret())
+        };
         let cb_target = ctx.args[2].get_i32().unwrap();
         let cb_data = ctx.args[3].get_i32().unwrap();
+        let max_conns = ctx.args[4].get_i32().unwrap();
+        let flags = ctx.args[5].get_i32().unwrap();
 
         let app = ctx.app.upgrade().unwrap();
 
-        match app.check_permission(&AppPermission::TcpListenAny)
-            .or_else(|_| app.check_permission(&AppPermission::TcpListen(addr.to_string()))) {
-                Ok(_) => {},
-                Err(_) => {
-                    derror!(
-                        logger!(&app.name),
-                        "TcpListenAny or TcpListen({}) permission is required",
-                        addr
-                    );
-                    return Some(ErrorCode::PermissionDenied.to_ret());
-                }
+        // Parsed before the permission check so that a malformed address
+        // is reported as such rather than as a denied one.
+        let saddr: SocketAddr = match addr.parse() {
+            Ok(v) => v,
+            Err(_) => {
+                derror!(
+                    logger!(&app.name),
+                    "Invalid listen address: {}",
+                    addr
+                );
+                return Some(ErrorCode::InvalidInput.to_ret());
             }
+        };
+
+        if let Err(code) = app.require_any_permission(&[
+            AppPermission::TcpListenAny,
+            AppPermission::TcpListen(addr.to_string())
+        ]) {
+            return Some(code.to_ret());
+        }
 
         let app_weak = ctx.app.clone();
 
-        let saddr: SocketAddr = match addr.parse() {
-            Ok(v) => v,
-            Err(_) => return Some(ErrorCode::InvalidInput.to_ret())
-        };
-        let listener = match tokio::net::TcpListener::bind(&saddr) {
+        let listener = match bind_listener(&saddr, flags & LISTEN_DUAL_STACK != 0) {
             Ok(v) => v,
             Err(e) => {
                 derror!(
                     logger!(&app.name),
-                    "Bind failed: {:?}",
+                    "Bind to {} failed: {:?}",
+                    addr,
                     e
                 );
                 return Some(ErrorCode::BindFail.to_ret());
@@ -149,56 +288,255 @@ impl TcpImpl {
         };
 
         let streams = self.streams.clone();
+        let n_conns: Rc<Cell<usize>> = Rc::new(Cell::new(0));
+
+        let (stop_tx, stop_rx) = futures::sync::oneshot::channel::<()>();
+        let listener_id = self.listeners.borrow_mut().insert(ListenerHandle {
+            local_addr: listener.local_addr().ok(),
+            _stop: stop_tx
+        });
 
         tokio::executor::current_thread::spawn(
             listener.incoming().for_each(move |stream| {
-                let (rh, wh) = stream.split();
-                let stream_id = streams.borrow_mut().insert((
-                    Some(rh),
-                    Some(wh)
-                ));
+                if max_conns > 0 && n_conns.get() >= max_conns as usize {
+                    dwarning!(
+                        logger!("(app)"),
+                        "Connection limit ({}) reached, dropping incoming connection",
+                        max_conns
+                    );
+                    return Ok(());
+                }
 
-                app_weak.upgrade().unwrap().invoke2(
-                    cb_target,
-                    cb_data,
-                    stream_id as _
-                );
+                let mut entry = StreamEntry::new(stream);
+                entry._conn_guard = Some(ConnectionGuard::new(n_conns.clone()));
+                entry.accepted = true;
+                let stream_id = streams.borrow_mut().insert(entry);
+
+                if let Some(app) = app_weak.upgrade() {
+                    app.invoke2(
+                        cb_target,
+                        cb_data,
+                        stream_id as _
+                    );
+                }
                 Ok(())
             }).map(|_| ()).map_err(move |e| {
                 derror!(logger!("(app)"), "Accept error: {:?}", e);
-            })
+            }).select(
+                stop_rx.then(|_| Ok(()))
+            ).map(|_| ()).map_err(|_| ())
         );
 
+        Some(Value::I32(listener_id as i32))
+    }
+
+    pub fn stop_listen(&self, ctx: InvokeContext) -> Option<Value> {
+        let listener_id = ctx.args[0].get_i32().unwrap() as usize;
+
+        let mut listeners = self.listeners.borrow_mut();
+        if !listeners.contains(listener_id) {
+            return Some(ErrorCode::NotFound.to_ret());
+        }
+        listeners.remove(listener_id);
+
         Some(ErrorCode::Success.to_ret())
     }
 
+    /// Closes the stream. A pending read or write is cancelled and calls
+    /// back with `Shutdown`.
     pub fn destroy(&self, ctx: InvokeContext) -> Option<Value> {
         let stream_id = ctx.args[0].get_i32().unwrap() as usize;
-        self.streams.borrow_mut().remove(stream_id);
+
+        let mut streams = self.streams.borrow_mut();
+        if streams.contains(stream_id) {
+            streams.remove(stream_id);
+        }
         None
     }
 
-    pub fn release_buffer(&self, ctx: InvokeContext) -> Option<Value> {
-        let buffer_id = ctx.args[0].get_i32().unwrap() as usize;
-        self.buffers.borrow_mut().remove(buffer_id);
-        None
+    pub fn peer_addr(&self, mut ctx: InvokeContext) -> Option<Value> {
+        let stream_id = ctx.args[0].get_i32().unwrap() as usize;
+
+        let addr = match self.streams.borrow().get(stream_id).and_then(|s| s.stream.0.peer_addr().ok()) {
+            Some(v) => format!("{}", v),
+            None => return Some(ErrorCode::NotFound.to_ret())
+        };
+
+        Some(copy_to_app(&mut ctx, 1, 2, addr.as_bytes()))
     }
 
-    pub fn take_buffer(&self, ctx: InvokeContext) -> Option<Value> {
-        let buffer_id = ctx.args[0].get_i32().unwrap() as usize;
-        let target_ptr = ctx.args[1].get_i32().unwrap() as usize;
-        let max_len = ctx.args[2].get_i32().unwrap() as usize;
+    /// Writes the address a listener is bound to into `(ptr, len)` and
+    /// returns its length. Useful to learn the port the OS picked for a
+    /// listener bound to port 0.
+    pub fn listener_addr(&self, mut ctx: InvokeContext) -> Option<Value> {
+        let listener_id = ctx.args[0].get_i32().unwrap() as usize;
+
+        let addr = match self.listeners.borrow().get(listener_id).and_then(|l| l.local_addr) {
+            Some(v) => format!("{}", v),
+            None => return Some(ErrorCode::NotFound.to_ret())
+        };
+
+        Some(copy_to_app(&mut ctx, 1, 2, addr.as_bytes()))
+    }
+
+    pub fn set_nodelay(&self, ctx: InvokeContext) -> Option<Value> {
+        let stream_id = ctx.args[0].get_i32().unwrap() as usize;
+        let enabled = ctx.args[1].get_i32().unwrap() != 0;
 
-        let buf = self.buffers.borrow_mut().remove(buffer_id);
+        let streams = self.streams.borrow();
+        let entry = match streams.get(stream_id) {
+            Some(v) => v,
+            None => return Some(ErrorCode::NotFound.to_ret())
+        };
+
+        Some(match entry.stream.0.set_nodelay(enabled) {
+            Ok(_) => ErrorCode::Success.to_ret(),
+            Err(e) => ErrorCode::from(e.kind()).to_ret()
+        })
+    }
+
+    /// `shutdown(stream_id, how)`, where `how` is 0 (read half), 1 (write
+    /// half) or 2 (both). The stream stays valid until `destroy`, so reads
+    /// can continue after the write half is closed.
+    pub fn shutdown(&self, ctx: InvokeContext) -> Option<Value> {
+        use std::net::Shutdown;
+
+        let stream_id = ctx.args[0].get_i32().unwrap() as usize;
+        let how = match ctx.args[1].get_i32().unwrap() {
+            0 => Shutdown::Read,
+            1 => Shutdown::Write,
+            2 => Shutdown::Both,
+            _ => return Some(ErrorCode::InvalidInput.to_ret())
+        };
+
+        let streams = self.streams.borrow();
+        let entry = match streams.get(stream_id) {
+            Some(v) => v,
+            None => return Some(ErrorCode::NotFound.to_ret())
+        };
 
-        if buf.len() > max_len {
-            panic!("take_buffer: buf.len() > max_len");
+        Some(match entry.stream.0.shutdown(how) {
+            Ok(_) => ErrorCode::Success.to_ret(),
+            Err(e) => ErrorCode::from(e.kind()).to_ret()
+        })
+    }
+
+    /// Sets the timeout for reads issued after this call. A read that is
+    /// already pending keeps the timeout it was started with.
+    ///
+    /// `millis <= 0` disables the timeout.
+    pub fn set_read_timeout(&self, ctx: InvokeContext) -> Option<Value> {
+        let stream_id = ctx.args[0].get_i32().unwrap() as usize;
+        let millis = ctx.args[1].get_i32().unwrap();
+
+        let mut streams = self.streams.borrow_mut();
+        let entry = match streams.get_mut(stream_id) {
+            Some(v) => v,
+            None => return Some(ErrorCode::NotFound.to_ret())
+        };
+
+        entry.read_timeout = if millis > 0 {
+            Some(Duration::from_millis(millis as u64))
+        } else {
+            None
+        };
+
+        Some(ErrorCode::Success.to_ret())
+    }
+
+    /// `set_idle_timeout(millis, cb_target, cb_data)`
+    ///
+    /// Shuts down accepted streams that have not completed a read or write
+    /// for `millis` milliseconds, then calls back with `(cb_data, stream_id)`.
+    /// The stream stays valid until the app `destroy`s it; pending reads see
+    /// EOF. Streams are checked about once a second, and all of them count
+    /// as active at the time of this call.
+    ///
+    /// `millis <= 0` disables the timeout, which is the default.
+    pub fn set_idle_timeout(&self, ctx: InvokeContext) -> Option<Value> {
+        let millis = ctx.args[0].get_i32().unwrap();
+        let cb_target = ctx.args[1].get_i32().unwrap();
+        let cb_data = ctx.args[2].get_i32().unwrap();
+
+        if millis <= 0 {
+            self.idle_timeout.set(None);
+            return Some(ErrorCode::Success.to_ret());
         }
 
-        let target_mem = &mut ctx.state.get_memory_mut()[target_ptr .. target_ptr + buf.len()];
-        target_mem.copy_from_slice(&buf);
+        self.idle_timeout.set(Some(IdleTimeout {
+            timeout: Duration::from_millis(millis as u64),
+            cb_target: cb_target,
+            cb_data: cb_data
+        }));
+
+        let now = Instant::now();
+        for (_, entry) in self.streams.borrow_mut().iter_mut() {
+            entry.last_activity = now;
+        }
+
+        if !self.reaper_running.get() {
+            self.reaper_running.set(true);
+            self.spawn_idle_reaper(ctx.app.clone());
+        }
+
+        Some(ErrorCode::Success.to_ret())
+    }
+
+    /// Runs until the idle timeout is disabled or the app goes away.
+    fn spawn_idle_reaper(&self, app_weak: Weak<ApplicationImpl>) {
+        let streams = Rc::downgrade(&self.streams);
+        let idle_timeout = self.idle_timeout.clone();
+        let running = self.reaper_running.clone();
+        let interval = Duration::from_millis(IDLE_SWEEP_INTERVAL_MS);
+
+        tokio::executor::current_thread::spawn(
+            tokio::timer::Interval::new(Instant::now() + interval, interval)
+                .map_err(|e| {
+                    derror!(logger!("(app)"), "Timer error: {:?}", e);
+                })
+                .for_each(move |_| {
+                    let (idle, streams, app) = match (idle_timeout.get(), streams.upgrade(), app_weak.upgrade()) {
+                        (Some(idle), Some(streams), Some(app)) => (idle, streams, app),
+                        _ => {
+                            running.set(false);
+                            return Err(());
+                        }
+                    };
+
+                    let now = Instant::now();
+                    let mut expired: Vec<usize> = Vec::new();
+                    for (id, entry) in streams.borrow_mut().iter_mut() {
+                        if entry.accepted && !entry.idle_closed
+                            && now.duration_since(entry.last_activity) >= idle.timeout {
+                            let _ = entry.stream.0.shutdown(::std::net::Shutdown::Both);
+                            entry.idle_closed = true;
+                            expired.push(id);
+                        }
+                    }
 
-        Some(Value::I32(buf.len() as i32))
+                    for id in expired {
+                        app.invoke2(
+                            idle.cb_target,
+                            idle.cb_data,
+                            id as i32
+                        );
+                    }
+                    Ok(())
+                })
+                .then(|_| Ok(()))
+        );
+    }
+
+    pub fn release_buffer(&self, ctx: InvokeContext) -> Option<Value> {
+        self.buffers.borrow_mut().release_buffer(ctx)
+    }
+
+    pub fn take_buffer(&self, ctx: InvokeContext) -> Option<Value> {
+        self.buffers.borrow_mut().take_buffer(ctx)
+    }
+
+    pub fn buffer_len(&self, ctx: InvokeContext) -> Option<Value> {
+        self.buffers.borrow().buffer_len(ctx)
     }
 
     pub fn read(&self, ctx: InvokeContext) -> Option<Value> {
@@ -207,88 +545,169 @@ impl TcpImpl {
         let cb_target = ctx.args[2].get_i32().unwrap();
         let cb_data = ctx.args[3].get_i32().unwrap();
 
-        let conn = match self.streams.borrow_mut()[stream_id].0.take() {
-            Some(v) => v,
-            None => {
+        let checkout = match self.streams.borrow_mut().get_mut(stream_id) {
+            Some(entry) => if entry.reading {
+                Err(ErrorCode::OngoingIo)
+            } else {
+                let (cancel_tx, cancel_rx) = futures::sync::oneshot::channel::<()>();
+                entry.reading = true;
+                entry.read_cancel = Some(cancel_tx);
+                Ok((entry.stream.clone(), entry.read_timeout, cancel_rx))
+            },
+            None => Err(ErrorCode::NotFound)
+        };
+        let (conn, timeout, cancel_rx) = match checkout {
+            Ok(v) => v,
+            Err(code) => {
                 ctx.app.upgrade().unwrap().invoke2(
                     cb_target,
                     cb_data,
-                    ErrorCode::OngoingIo.to_i32()
+                    code.to_i32()
                 );
                 return None;
             }
         };
         let streams = self.streams.clone();
         let buffers = self.buffers.clone();
+        let app_weak = ctx.app.clone();
 
-        let app_weak1 = ctx.app.clone();
-        let app_weak2 = ctx.app.clone();
+        let start = Instant::now();
+        let read_fut = AsyncReadFuture::new(conn.clone(), read_len, &self.buffers);
+        let read_fut: Box<Future<Item = _, Error = tokio::io::Error>> = match timeout {
+            Some(t) => Box::new(
+                tokio::timer::Timeout::new(read_fut, t).map_err(|e| {
+                    if e.is_elapsed() {
+                        tokio::io::Error::new(
+                            tokio::io::ErrorKind::TimedOut,
+                            "read timed out"
+                        )
+                    } else if e.is_inner() {
+                        e.into_inner().unwrap()
+                    } else {
+                        tokio::io::Error::new(
+                            tokio::io::ErrorKind::Other,
+                            "timer error"
+                        )
+                    }
+                })
+            ),
+            None => Box::new(read_fut)
+        };
 
+        // A read that completes in the same poll as a `destroy` still
+        // delivers its data, since `read_fut` is polled first.
         tokio::executor::current_thread::spawn(
-            AsyncReadFuture::new(conn, read_len)
-                .map(move |(stream, data)| {
-                    streams.borrow_mut()[stream_id].0 = Some(stream);
-                    let buffer_id = buffers.borrow_mut().insert(data);
+            read_fut.select2(cancel_rx).then(move |result| {
+                if let Some(entry) = entry_for(&mut streams.borrow_mut(), stream_id, &conn) {
+                    entry.reading = false;
+                    entry.read_cancel = None;
+                    entry.last_activity = Instant::now();
+                }
 
-                    app_weak1.upgrade().unwrap().invoke2(
-                        cb_target,
-                        cb_data,
-                        buffer_id as _
-                    );
-                })
-                .map_err(move |e| {
-                    derror!(logger!("(app)"), "Read error: {:?}", e);
-                    app_weak2.upgrade().unwrap().invoke2(
+                let ret = match result {
+                    Ok(Either::A(((_, data, len), _))) => buffers.borrow_mut().insert(data, len) as i32,
+                    Err(Either::A((e, _))) => {
+                        derror!(logger!("(app)"), "Read error: {:?}", e);
+                        ErrorCode::from(e.kind()).to_i32()
+                    },
+                    // Destroyed while pending.
+                    Ok(Either::B(_)) | Err(Either::B(_)) => ErrorCode::Shutdown.to_i32()
+                };
+                if let Some(app) = app_weak.upgrade() {
+                    app.record_latency("tcp_read", start);
+                    app.invoke2(
                         cb_target,
                         cb_data,
-                        -1
+                        ret
                     );
-                })
+                }
+                Ok(())
+            })
         );
 
         None
     }
 
+    /// Writes all of `(data_ptr, data_len)` to the stream.
+    ///
+    /// Calls back with `(cb_data, ret, n_bytes_written)`, where `ret` is
+    /// `data_len` on success or a negative error code. On failure,
+    /// `n_bytes_written` tells how much of the data was sent, so that the
+    /// app can resume from there.
     pub fn write(&self, ctx: InvokeContext) -> Option<Value> {
         let stream_id = ctx.args[0].get_i32().unwrap() as usize;
-        let data = ctx.extract_bytes(1, 2);
         let cb_target = ctx.args[3].get_i32().unwrap();
         let cb_data = ctx.args[4].get_i32().unwrap();
 
-        let conn = match self.streams.borrow_mut()[stream_id].1.take() {
+        let data = match ctx.try_extract_bytes(1, 2) {
             Some(v) => v,
             None => {
-                ctx.app.upgrade().unwrap().invoke2(
+                ctx.app.upgrade().unwrap().invoke3(
                     cb_target,
                     cb_data,
-                    ErrorCode::OngoingIo.to_i32()
+                    ErrorCode::InvalidInput.to_i32(),
+                    0
                 );
                 return None;
             }
         };
-        let streams = self.streams.clone();
 
-        let app_weak1 = ctx.app.clone();
-        let app_weak2 = ctx.app.clone();
+        let checkout = match self.streams.borrow_mut().get_mut(stream_id) {
+            Some(entry) => if entry.writing {
+                Err(ErrorCode::OngoingIo)
+            } else {
+                let (cancel_tx, cancel_rx) = futures::sync::oneshot::channel::<()>();
+                entry.writing = true;
+                entry.write_cancel = Some(cancel_tx);
+                Ok((entry.stream.clone(), cancel_rx))
+            },
+            None => Err(ErrorCode::NotFound)
+        };
+        let (conn, cancel_rx) = match checkout {
+            Ok(v) => v,
+            Err(code) => {
+                ctx.app.upgrade().unwrap().invoke3(
+                    cb_target,
+                    cb_data,
+                    code.to_i32(),
+                    0
+                );
+                return None;
+            }
+        };
+        let streams = self.streams.clone();
+        let app_weak = ctx.app.clone();
 
         let data_len = data.len();
+        let start = Instant::now();
 
         tokio::executor::current_thread::spawn(
-            tokio::io::write_all(conn, data.to_vec()).map(move |(a, _)| {
-                streams.borrow_mut()[stream_id].1 = Some(a);
+            AsyncWriteAllFuture::new(conn.clone(), data.to_vec()).select2(cancel_rx).then(move |result| {
+                if let Some(entry) = entry_for(&mut streams.borrow_mut(), stream_id, &conn) {
+                    entry.writing = false;
+                    entry.write_cancel = None;
+                    entry.last_activity = Instant::now();
+                }
 
-                app_weak1.upgrade().unwrap().invoke2(
-                    cb_target,
-                    cb_data,
-                    data_len as _
-                );
-            }).or_else(move |e| {
-                derror!(logger!("(app)"), "Write error: {:?}", e);
-                app_weak2.upgrade().unwrap().invoke2(
-                    cb_target,
-                    cb_data,
-                    -1
-                );
+                let (ret, written) = match result {
+                    Ok(Either::A((n, _))) => (data_len as i32, n),
+                    Err(Either::A(((e, n), _))) => {
+                        derror!(logger!("(app)"), "Write error: {:?}", e);
+                        (ErrorCode::from(e.kind()).to_i32(), n)
+                    },
+                    // Destroyed while pending. How much was written is not
+                    // tracked once the write future is dropped.
+                    Ok(Either::B(_)) | Err(Either::B(_)) => (ErrorCode::Shutdown.to_i32(), 0)
+                };
+                if let Some(app) = app_weak.upgrade() {
+                    app.record_latency("tcp_write", start);
+                    app.invoke3(
+                        cb_target,
+                        cb_data,
+                        ret,
+                        written as _
+                    );
+                }
                 Ok(())
             })
         );
@@ -297,32 +716,183 @@ impl TcpImpl {
     }
 }
 
+/// `listen` flag: accept IPv4 connections on an IPv6 listener.
+pub const LISTEN_DUAL_STACK: i32 = 1;
+
+/// Binds a listener on `addr`. With `dual_stack`, an IPv6 socket is bound
+/// with `IPV6_V6ONLY` cleared instead of left at the platform default.
+fn bind_listener(addr: &SocketAddr, dual_stack: bool) -> io::Result<tokio::net::TcpListener> {
+    if !dual_stack || !addr.is_ipv6() {
+        return tokio::net::TcpListener::bind(addr);
+    }
+
+    let builder = net2::TcpBuilder::new_v6()?;
+    builder.only_v6(false)?;
+    builder.reuse_address(true)?;
+    let listener = builder.bind(addr)?.listen(1024)?;
+
+    tokio::net::TcpListener::from_std(listener, &tokio::reactor::Handle::current())
+}
+
+/// Copies `data` into the app buffer at `(ptr, len)` and returns its length,
+/// or `InvalidInput` if the buffer is too small or out of bounds.
+fn copy_to_app(ctx: &mut InvokeContext, ptr_arg_index: usize, len_arg_index: usize, data: &[u8]) -> Value {
+    let target_mem = match ctx.try_extract_bytes_mut(ptr_arg_index, len_arg_index) {
+        Some(v) => v,
+        None => return ErrorCode::InvalidInput.to_ret()
+    };
+    if data.len() > target_mem.len() {
+        return ErrorCode::InvalidInput.to_ret();
+    }
+    target_mem[0 .. data.len()].copy_from_slice(data);
+
+    Value::I32(data.len() as i32)
+}
+
+/// Read buffers handed out to the app, plus a free list of spare
+/// allocations so that a tight read loop doesn't allocate on every read.
+pub struct BufferPool {
+    // (buffer, logical length)
+    buffers: Slab<(Box<[u8]>, usize)>,
+    free: Vec<Box<[u8]>>
+}
+
+const MAX_FREE_BUFFERS: usize = 64;
+
+/// Larger buffers are freed instead of being kept on the free list, so
+/// that one big read doesn't pin its memory for the life of the app.
+const MAX_RECYCLED_BUFFER_SIZE: usize = 64 * 1024;
+
+impl BufferPool {
+    pub fn new() -> BufferPool {
+        BufferPool {
+            buffers: Slab::new(),
+            free: Vec::new()
+        }
+    }
+
+    /// Returns the smallest spare buffer of at least `min_len` bytes,
+    /// allocating one if none is available.
+    pub fn alloc(&mut self, min_len: usize) -> Box<[u8]> {
+        let best = self.free.iter()
+            .enumerate()
+            .filter(|&(_, b)| b.len() >= min_len)
+            .min_by_key(|&(_, b)| b.len())
+            .map(|(i, _)| i);
+        match best {
+            Some(i) => self.free.swap_remove(i),
+            None => vec! [ 0; min_len ].into_boxed_slice()
+        }
+    }
+
+    /// Puts a buffer back onto the free list, unless the list is full or
+    /// the buffer is larger than `MAX_RECYCLED_BUFFER_SIZE`.
+    pub fn recycle(&mut self, buf: Box<[u8]>) {
+        if self.free.len() < MAX_FREE_BUFFERS && buf.len() <= MAX_RECYCLED_BUFFER_SIZE {
+            self.free.push(buf);
+        }
+    }
+
+    pub fn insert(&mut self, buf: Box<[u8]>, len: usize) -> usize {
+        self.buffers.insert((buf, len))
+    }
+
+    pub fn remove(&mut self, id: usize) -> (Box<[u8]>, usize) {
+        self.buffers.remove(id)
+    }
+
+    /// Frees buffer `id` if it exists.
+    pub fn release(&mut self, id: usize) {
+        if self.buffers.contains(id) {
+            let (buf, _) = self.remove(id);
+            self.recycle(buf);
+        }
+    }
+
+    /// Whether no buffers are held by the app.
+    pub fn is_empty(&self) -> bool {
+        self.buffers.is_empty()
+    }
+
+    /// Implements the `release_buffer(buffer_id)` namespace function.
+    /// Unknown ids are ignored.
+    pub fn release_buffer(&mut self, ctx: InvokeContext) -> Option<Value> {
+        let buffer_id = ctx.args[0].get_i32().unwrap() as usize;
+        self.release(buffer_id);
+        None
+    }
+
+    /// Implements the `buffer_len(buffer_id)` namespace function.
+    pub fn buffer_len(&self, ctx: InvokeContext) -> Option<Value> {
+        let buffer_id = ctx.args[0].get_i32().unwrap() as usize;
+        match self.buffers.get(buffer_id) {
+            Some(&(_, len)) => Some(Value::I32(len as i32)),
+            None => Some(ErrorCode::NotFound.to_ret())
+        }
+    }
+
+    /// Implements the `take_buffer(buffer_id, ptr, max_len)` namespace function.
+    ///
+    /// Copies the buffer into `(ptr, max_len)`, frees it and returns its
+    /// length. If the destination is smaller than the buffer or outside app
+    /// memory, returns `InvalidInput` and leaves the buffer in place, so
+    /// that the app can retry with a larger destination (see `buffer_len`).
+    pub fn take_buffer(&mut self, mut ctx: InvokeContext) -> Option<Value> {
+        let buffer_id = ctx.args[0].get_i32().unwrap() as usize;
+        let max_len = ctx.args[2].get_i32().unwrap() as u32 as usize;
+
+        let len = match self.buffers.get(buffer_id) {
+            Some(&(_, len)) => len,
+            None => return Some(ErrorCode::NotFound.to_ret())
+        };
+        if len > max_len {
+            return Some(ErrorCode::InvalidInput.to_ret());
+        }
+
+        match ctx.try_extract_bytes_mut(1, 2) {
+            Some(target_mem) => target_mem[0..len].copy_from_slice(&self.buffers[buffer_id].0[0..len]),
+            None => return Some(ErrorCode::InvalidInput.to_ret())
+        }
+
+        let (buf, _) = self.remove(buffer_id);
+        self.recycle(buf);
+
+        Some(Value::I32(len as i32))
+    }
+}
+
 pub struct AsyncReadFuture<T: AsyncRead> {
     inner: Option<T>,
-    buf: Vec<u8>
+    buf: Option<Box<[u8]>>,
+    len: usize
 }
 
 impl<T: AsyncRead> AsyncReadFuture<T> {
-    fn new(inner: T, len: usize) -> AsyncReadFuture<T> {
+    pub fn new(inner: T, len: usize, pool: &RefCell<BufferPool>) -> AsyncReadFuture<T> {
         AsyncReadFuture {
             inner: Some(inner),
-            buf: vec! [ 0; len ]
+            buf: Some(pool.borrow_mut().alloc(len)),
+            len: len
         }
     }
 }
 
 impl<T: AsyncRead> Future for AsyncReadFuture<T> {
-    type Item = (T, Box<[u8]>);
+    type Item = (T, Box<[u8]>, usize);
     type Error = tokio::io::Error;
 
     fn poll(&mut self) -> futures::Poll<Self::Item, Self::Error> {
-        let result = self.inner.as_mut().unwrap().poll_read(&mut self.buf);
+        let result = {
+            let buf = self.buf.as_mut().unwrap();
+            self.inner.as_mut().unwrap().poll_read(&mut buf[0..self.len])
+        };
         match result {
             Ok(tokio::prelude::Async::Ready(n_bytes)) => Ok(
                 futures::prelude::Async::Ready(
                     (
                         self.inner.take().unwrap(),
-                        self.buf[0..n_bytes].to_vec().into_boxed_slice()
+                        self.buf.take().unwrap(),
+                        n_bytes
                     )
                 )
             ),
@@ -333,3 +903,243 @@ impl<T: AsyncRead> Future for AsyncReadFuture<T> {
         }
     }
 }
+
+/// Like `tokio::io::write_all`, but reports how many bytes were written
+/// when it fails.
+pub struct AsyncWriteAllFuture<T: AsyncWrite> {
+    inner: T,
+    data: Vec<u8>,
+    written: usize
+}
+
+impl<T: AsyncWrite> AsyncWriteAllFuture<T> {
+    pub fn new(inner: T, data: Vec<u8>) -> AsyncWriteAllFuture<T> {
+        AsyncWriteAllFuture {
+            inner: inner,
+            data: data,
+            written: 0
+        }
+    }
+}
+
+impl<T: AsyncWrite> Future for AsyncWriteAllFuture<T> {
+    type Item = usize;
+    type Error = (tokio::io::Error, usize);
+
+    fn poll(&mut self) -> futures::Poll<Self::Item, Self::Error> {
+        while self.written < self.data.len() {
+            match self.inner.poll_write(&self.data[self.written..]) {
+                Ok(tokio::prelude::Async::Ready(0)) => return Err((
+                    tokio::io::Error::new(
+                        tokio::io::ErrorKind::WriteZero,
+                        "write returned zero"
+                    ),
+                    self.written
+                )),
+                Ok(tokio::prelude::Async::Ready(n)) => self.written += n,
+                Ok(tokio::prelude::Async::NotReady) => return Ok(
+                    futures::prelude::Async::NotReady
+                ),
+                Err(e) => return Err((e, self.written))
+            }
+        }
+        Ok(futures::prelude::Async::Ready(self.written))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::net::{SocketAddr, TcpStream as StdTcpStream};
+    use std::time::{Duration, Instant};
+    use futures;
+    use tokio::runtime::current_thread::Runtime;
+    use tokio::timer::Delay;
+    use wasm_core::value::Value;
+    use config::AppPermission;
+    use super::super::super::error::ErrorCode;
+    use super::super::super::testutil::*;
+    use super::{TcpImpl, StreamEntry, BufferPool, MAX_RECYCLED_BUFFER_SIZE};
+
+    const BAD_IDS: &[i32] = &[-1, 0, 1, 1000];
+
+    fn ret_of(v: Option<Value>) -> i32 {
+        v.unwrap().get_i32().unwrap()
+    }
+
+    #[test]
+    fn invalid_ids_are_rejected() {
+        let (_container, app) = idle_app("tcp-ids", &[]);
+        let tcp = TcpImpl::new();
+        let mut state = TestState::new(64);
+
+        for &id in BAD_IDS {
+            let id = Value::I32(id);
+            assert!(call(&app, &mut state, &[id, Value::I32(16), Value::I32(0), Value::I32(0)], |ctx| tcp.read(ctx)).is_none());
+            assert!(call(&app, &mut state, &[id, Value::I32(0), Value::I32(4), Value::I32(0), Value::I32(0)], |ctx| tcp.write(ctx)).is_none());
+            assert!(call(&app, &mut state, &[id], |ctx| tcp.destroy(ctx)).is_none());
+            assert!(call(&app, &mut state, &[id], |ctx| tcp.release_buffer(ctx)).is_none());
+            assert_eq!(
+                ret_of(call(&app, &mut state, &[id, Value::I32(0), Value::I32(64)], |ctx| tcp.take_buffer(ctx))),
+                ErrorCode::NotFound.to_i32()
+            );
+            assert_eq!(
+                ret_of(call(&app, &mut state, &[id, Value::I32(0), Value::I32(64)], |ctx| tcp.peer_addr(ctx))),
+                ErrorCode::NotFound.to_i32()
+            );
+        }
+
+        assert!(!app.is_faulted());
+
+        // Every write error reaches the app through the same three-argument
+        // callback as a completed write.
+        let (_container, app) = callback_app("tcp-write-cb", 3);
+        for &id in BAD_IDS {
+            let id = Value::I32(id);
+            assert!(call(&app, &mut state, &[id, Value::I32(0), Value::I32(4), Value::I32(0), Value::I32(0)], |ctx| tcp.write(ctx)).is_none());
+        }
+        assert!(call(&app, &mut state, &[Value::I32(0), Value::I32(60), Value::I32(16), Value::I32(0), Value::I32(0)], |ctx| tcp.write(ctx)).is_none());
+        assert!(!app.is_faulted());
+    }
+
+    #[test]
+    fn destroyed_ids_are_rejected() {
+        let mut rt = Runtime::new().unwrap();
+        let (_container, app) = idle_app("tcp-destroyed", &[]);
+        let tcp = TcpImpl::new();
+        let mut state = TestState::new(64);
+
+        let listener = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr: SocketAddr = listener.local_addr().unwrap();
+        let stream = rt.block_on(::tokio::net::TcpStream::connect(&addr)).unwrap();
+        let stream_id = Value::I32(tcp.streams.borrow_mut().insert(StreamEntry::new(stream)) as i32);
+
+        assert!(call(&app, &mut state, &[stream_id], |ctx| tcp.destroy(ctx)).is_none());
+        assert!(tcp.streams.borrow().is_empty());
+
+        // A second destroy, and any use of the stale id, is a no-op.
+        assert!(call(&app, &mut state, &[stream_id], |ctx| tcp.destroy(ctx)).is_none());
+        assert!(call(&app, &mut state, &[stream_id, Value::I32(16), Value::I32(0), Value::I32(0)], |ctx| tcp.read(ctx)).is_none());
+        assert!(call(&app, &mut state, &[stream_id, Value::I32(0), Value::I32(4), Value::I32(0), Value::I32(0)], |ctx| tcp.write(ctx)).is_none());
+        assert_eq!(
+            ret_of(call(&app, &mut state, &[stream_id, Value::I32(0), Value::I32(64)], |ctx| tcp.peer_addr(ctx))),
+            ErrorCode::NotFound.to_i32()
+        );
+        assert!(tcp.streams.borrow().is_empty());
+        assert!(!app.is_faulted());
+    }
+
+    #[test]
+    fn taken_buffer_ids_are_rejected() {
+        let (_container, app) = idle_app("tcp-buffers", &[]);
+        let tcp = TcpImpl::new();
+        let mut state = TestState::new(64);
+
+        let buffer_id = Value::I32(tcp.buffers.borrow_mut().insert(vec! [ 1, 2, 3 ].into_boxed_slice(), 3) as i32);
+
+        // Too small a destination leaves the buffer for a retry sized by
+        // `buffer_len`.
+        assert_eq!(
+            ret_of(call(&app, &mut state, &[buffer_id, Value::I32(0), Value::I32(2)], |ctx| tcp.take_buffer(ctx))),
+            ErrorCode::InvalidInput.to_i32()
+        );
+        assert_eq!(ret_of(call(&app, &mut state, &[buffer_id], |ctx| tcp.buffer_len(ctx))), 3);
+
+        assert_eq!(ret_of(call(&app, &mut state, &[buffer_id, Value::I32(0), Value::I32(3)], |ctx| tcp.take_buffer(ctx))), 3);
+        assert_eq!(&state.memory[0..3], &[1, 2, 3]);
+
+        assert_eq!(
+            ret_of(call(&app, &mut state, &[buffer_id, Value::I32(0), Value::I32(64)], |ctx| tcp.take_buffer(ctx))),
+            ErrorCode::NotFound.to_i32()
+        );
+        assert_eq!(
+            ret_of(call(&app, &mut state, &[buffer_id], |ctx| tcp.buffer_len(ctx))),
+            ErrorCode::NotFound.to_i32()
+        );
+        assert!(call(&app, &mut state, &[buffer_id], |ctx| tcp.release_buffer(ctx)).is_none());
+    }
+
+    #[test]
+    fn buffer_pool_reuses_best_fitting_small_buffers() {
+        let mut pool = BufferPool::new();
+
+        pool.recycle(vec! [ 0; MAX_RECYCLED_BUFFER_SIZE + 1 ].into_boxed_slice());
+        assert!(pool.free.is_empty());
+
+        pool.recycle(vec! [ 0; 4096 ].into_boxed_slice());
+        pool.recycle(vec! [ 0; 16 ].into_boxed_slice());
+        pool.recycle(vec! [ 0; 1024 ].into_boxed_slice());
+
+        assert_eq!(pool.alloc(10).len(), 16);
+        assert_eq!(pool.alloc(10).len(), 1024);
+        assert_eq!(pool.alloc(2000).len(), 4096);
+        assert_eq!(pool.alloc(10).len(), 10);
+    }
+
+    /// Drives `rt` until `cond` holds, for at most a few seconds.
+    fn run_until<F: Fn() -> bool>(rt: &mut Runtime, cond: F) {
+        for _ in 0..200 {
+            if cond() {
+                return;
+            }
+            rt.block_on(Delay::new(Instant::now() + Duration::from_millis(10))).unwrap();
+        }
+        panic!("condition not reached");
+    }
+
+    /// Whether the server side of `client` has been closed.
+    fn is_closed(client: &mut StdTcpStream) -> bool {
+        client.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        let mut buf = [0; 1];
+        match client.read(&mut buf) {
+            Ok(0) => true,
+            Ok(_) => false,
+            Err(e) => e.kind() == ::std::io::ErrorKind::ConnectionReset
+        }
+    }
+
+    #[test]
+    fn listen_enforces_max_conns() {
+        let mut rt = Runtime::new().unwrap();
+        let (_container, app) = idle_app("tcp-max-conns", &[AppPermission::TcpListenAny]);
+        let tcp = TcpImpl::new();
+        let mut state = TestState::new(64);
+
+        let bind_addr = b"127.0.0.1:0";
+        state.memory[0..bind_addr.len()].copy_from_slice(bind_addr);
+
+        // `listen` spawns the accept loop, so it has to run on the runtime.
+        let listener_id = rt.block_on(futures::future::lazy(|| {
+            Ok::<_, ()>(call(&app, &mut state, &[
+                Value::I32(0),
+                Value::I32(bind_addr.len() as i32),
+                Value::I32(0),
+                Value::I32(0),
+                Value::I32(2),
+                Value::I32(0)
+            ], |ctx| tcp.listen(ctx)).unwrap())
+        })).unwrap();
+        assert!(listener_id.get_i32().unwrap() >= 0);
+
+        let addr_len = ret_of(call(&app, &mut state, &[listener_id, Value::I32(32), Value::I32(32)], |ctx| tcp.listener_addr(ctx)));
+        let addr: SocketAddr = ::std::str::from_utf8(&state.memory[32..32 + addr_len as usize]).unwrap().parse().unwrap();
+
+        // Over the limit of two, the third connection is closed right away.
+        let mut clients: Vec<StdTcpStream> = (0..3).map(|_| StdTcpStream::connect(addr).unwrap()).collect();
+        run_until(&mut rt, || tcp.streams.borrow().len() == 2);
+        // Gives the accept loop time to get to the third connection.
+        rt.block_on(Delay::new(Instant::now() + Duration::from_millis(100))).unwrap();
+        assert!(is_closed(&mut clients[2]));
+        assert!(!is_closed(&mut clients[0]));
+        assert_eq!(tcp.streams.borrow().len(), 2);
+
+        // Destroying a stream frees its slot.
+        let first_id = tcp.streams.borrow().iter().next().unwrap().0;
+        call(&app, &mut state, &[Value::I32(first_id as i32)], |ctx| tcp.destroy(ctx));
+        assert_eq!(tcp.streams.borrow().len(), 1);
+
+        let mut late = StdTcpStream::connect(addr).unwrap();
+        run_until(&mut rt, || tcp.streams.borrow().len() == 2);
+        assert!(!is_closed(&mut late));
+    }
+}
@@ -1,39 +1,225 @@
+use config::AppPermission;
 use super::super::namespace::InvokeContext;
+use super::super::error::ErrorCode;
 use wasm_core::value::Value;
+use std::rc::Rc;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
 
 use futures;
+use futures::Future;
+use futures::future::Either;
 use tokio;
+use tokio::timer::Delay;
 
 decl_namespace!(
     TimerNs,
     "timer",
     TimerImpl,
     now_millis,
-    set_immediate
+    monotonic_millis,
+    set_immediate,
+    set_timeout,
+    clear_timeout
 );
 
-pub struct TimerImpl;
+pub struct TimerImpl {
+    epoch: Instant,
+    // Dropping a sender cancels the corresponding timeout. Pending timers
+    // only hold weak references, so dropping this cancels all of them.
+    timeouts: Rc<RefCell<BTreeMap<i32, futures::sync::oneshot::Sender<()>>>>,
+    // Ids are not reused right away, so that clearing a timer that has
+    // already fired can't cancel a newer one.
+    next_id: Cell<i32>
+}
 
 impl TimerImpl {
-    pub fn now_millis(&self, _ctx: InvokeContext) -> Option<Value> {
+    pub fn new() -> TimerImpl {
+        TimerImpl {
+            epoch: Instant::now(),
+            timeouts: Rc::new(RefCell::new(BTreeMap::new())),
+            next_id: Cell::new(0)
+        }
+    }
+
+    fn add_timeout(&self, cancel_tx: futures::sync::oneshot::Sender<()>) -> i32 {
+        let mut timeouts = self.timeouts.borrow_mut();
+
+        let mut id = self.next_id.get();
+        while timeouts.contains_key(&id) {
+            id = next_timer_id(id);
+        }
+        self.next_id.set(next_timer_id(id));
+
+        timeouts.insert(id, cancel_tx);
+        id
+    }
+
+    /// Wall clock time. Requires the `ReadClock` permission, so that apps
+    /// can be denied access to it for determinism.
+    pub fn now_millis(&self, ctx: InvokeContext) -> Option<Value> {
         use chrono;
+
+        let app = ctx.app.upgrade().unwrap();
+        if let Err(code) = app.require_any_permission(&[AppPermission::ReadClock]) {
+            return Some(Value::I64(code.to_i32() as i64));
+        }
+
         let utc_time: chrono::DateTime<chrono::Utc> = chrono::Utc::now();
         Some(Value::I64(utc_time.timestamp_millis()))
     }
 
+    /// Milliseconds elapsed since the app was loaded. Never goes backwards.
+    pub fn monotonic_millis(&self, _ctx: InvokeContext) -> Option<Value> {
+        let elapsed = Instant::now().duration_since(self.epoch);
+        Some(Value::I64(
+            (elapsed.as_secs() * 1000 + (elapsed.subsec_nanos() / 1000000) as u64) as i64
+        ))
+    }
+
     pub fn set_immediate(&self, ctx: InvokeContext) -> Option<Value> {
         let app_weak = ctx.app.clone();
         let cb_target = ctx.args[0].get_i32().unwrap();
         let cb_data = ctx.args[1].get_i32().unwrap();
 
         tokio::executor::current_thread::spawn(futures::future::lazy(move || {
-            app_weak.upgrade().unwrap().invoke1(
-                cb_target,
-                cb_data
-            );
+            if let Some(app) = app_weak.upgrade() {
+                app.invoke1(
+                    cb_target,
+                    cb_data
+                );
+            }
             Ok(())
         }));
 
         None
     }
+
+    /// Calls back with `(cb_data, ret)` after `millis` milliseconds, unless
+    /// cancelled by `clear_timeout` first. `ret` is zero, or a negative
+    /// error code if the timer failed. Returns the timer id.
+    pub fn set_timeout(&self, ctx: InvokeContext) -> Option<Value> {
+        let millis = ctx.args[0].get_i64().unwrap();
+        let cb_target = ctx.args[1].get_i32().unwrap();
+        let cb_data = ctx.args[2].get_i32().unwrap();
+
+        let millis = if millis < 0 { 0 } else { millis as u64 };
+
+        let (cancel_tx, cancel_rx) = futures::sync::oneshot::channel::<()>();
+        let timer_id = self.add_timeout(cancel_tx);
+
+        let timeouts = Rc::downgrade(&self.timeouts);
+        let app_weak = ctx.app.clone();
+
+        // `cancel_rx` is polled first so that a cleared timer never fires,
+        // even if its deadline has also passed.
+        tokio::executor::current_thread::spawn(
+            cancel_rx.select2(
+                Delay::new(Instant::now() + Duration::from_millis(millis))
+            ).then(move |result| {
+                let ret = match result {
+                    Ok(Either::B(_)) => ErrorCode::Success.to_i32(),
+                    Err(Either::B((e, _))) => {
+                        derror!(logger!("(app)"), "Timer error: {:?}", e);
+                        ErrorCode::Generic.to_i32()
+                    },
+                    // Cancelled.
+                    _ => return Ok(())
+                };
+                if let Some(timeouts) = timeouts.upgrade() {
+                    timeouts.borrow_mut().remove(&timer_id);
+                }
+                if let Some(app) = app_weak.upgrade() {
+                    app.invoke2(
+                        cb_target,
+                        cb_data,
+                        ret
+                    );
+                }
+                Ok(())
+            })
+        );
+
+        Some(Value::I32(timer_id))
+    }
+
+    /// Returns `NotFound` if the timer has already fired or been cleared.
+    pub fn clear_timeout(&self, ctx: InvokeContext) -> Option<Value> {
+        let timer_id = ctx.args[0].get_i32().unwrap();
+
+        match self.timeouts.borrow_mut().remove(&timer_id) {
+            Some(_) => Some(ErrorCode::Success.to_ret()),
+            None => Some(ErrorCode::NotFound.to_ret())
+        }
+    }
+}
+
+fn next_timer_id(id: i32) -> i32 {
+    if id == ::std::i32::MAX {
+        0
+    } else {
+        id + 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use futures;
+    use futures::sync::oneshot;
+    use tokio::runtime::current_thread::Runtime;
+    use wasm_core::value::Value;
+    use super::super::super::testutil::*;
+    use super::TimerImpl;
+
+    #[test]
+    fn timer_ids_are_not_reused() {
+        let timer = TimerImpl::new();
+
+        let (tx, _rx) = oneshot::channel();
+        let first = timer.add_timeout(tx);
+        timer.timeouts.borrow_mut().remove(&first);
+
+        let (tx, _rx) = oneshot::channel();
+        let second = timer.add_timeout(tx);
+        assert!(second != first);
+        assert!(!timer.timeouts.borrow().contains_key(&first));
+    }
+
+    #[test]
+    fn timer_ids_skip_live_timers_on_wraparound() {
+        let timer = TimerImpl::new();
+
+        let (tx, _rx) = oneshot::channel();
+        let live = timer.add_timeout(tx);
+        timer.next_id.set(::std::i32::MAX);
+
+        let (tx, _rx) = oneshot::channel();
+        assert_eq!(timer.add_timeout(tx), ::std::i32::MAX);
+        let (tx, _rx) = oneshot::channel();
+        let wrapped = timer.add_timeout(tx);
+        assert!(wrapped >= 0 && wrapped != live);
+    }
+
+    #[test]
+    fn dropping_timer_cancels_pending_timeouts() {
+        let mut rt = Runtime::new().unwrap();
+        // Any callback would trap and fault the app.
+        let (_container, app) = callback_app("timer-drop", 0);
+        let mut state = TestState::new(0);
+
+        rt.block_on(futures::future::lazy(|| {
+            let timer = TimerImpl::new();
+            let args = [Value::I64(5000), Value::I32(0), Value::I32(0)];
+            call(&app, &mut state, &args, |ctx| timer.set_timeout(ctx));
+            Ok::<(), ()>(())
+        })).unwrap();
+
+        // Nothing is left to wait for once the timer is gone.
+        let start = Instant::now();
+        rt.run().unwrap();
+        assert!(start.elapsed() < Duration::from_millis(1000));
+        assert!(!app.is_faulted());
+    }
 }
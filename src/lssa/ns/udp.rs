@@ -0,0 +1,407 @@
+use config::AppPermission;
+use super::super::namespace::InvokeContext;
+use super::super::error::ErrorCode;
+use super::tcp::BufferPool;
+use wasm_core::value::Value;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::cell::RefCell;
+use slab::Slab;
+
+use futures;
+use futures::Future;
+use futures::future::Either;
+use tokio;
+use tokio::net::UdpSocket;
+
+decl_namespace!(
+    UdpNs,
+    "udp",
+    UdpImpl,
+    release_buffer,
+    take_buffer,
+    buffer_len,
+    bind,
+    send_to,
+    recv_from,
+    destroy
+);
+
+pub struct UdpImpl {
+    sockets: Rc<RefCell<Slab<SocketEntry>>>,
+    buffers: Rc<RefCell<BufferPool>>
+}
+
+struct SocketEntry {
+    socket: Rc<RefCell<UdpSocket>>,
+    // Set while a send/receive on this socket is pending.
+    sending: bool,
+    receiving: bool,
+    // Dropped along with the entry on `destroy`, which cancels the
+    // pending send/receive.
+    send_cancel: Option<futures::sync::oneshot::Sender<()>>,
+    recv_cancel: Option<futures::sync::oneshot::Sender<()>>
+}
+
+impl SocketEntry {
+    fn new(socket: UdpSocket) -> SocketEntry {
+        SocketEntry {
+            socket: Rc::new(RefCell::new(socket)),
+            sending: false,
+            receiving: false,
+            send_cancel: None,
+            recv_cancel: None
+        }
+    }
+}
+
+/// Returns the entry at `socket_id` only if it still holds `socket`. After a
+/// `destroy`, the id may already belong to another socket.
+fn entry_for<'a>(
+    sockets: &'a mut Slab<SocketEntry>,
+    socket_id: usize,
+    socket: &Rc<RefCell<UdpSocket>>
+) -> Option<&'a mut SocketEntry> {
+    sockets.get_mut(socket_id).filter(|e| Rc::ptr_eq(&e.socket, socket))
+}
+
+impl UdpImpl {
+    pub fn new() -> UdpImpl {
+        UdpImpl {
+            sockets: Rc::new(RefCell::new(Slab::new())),
+            buffers: Rc::new(RefCell::new(BufferPool::new()))
+        }
+    }
+
+    pub fn bind(&self, ctx: InvokeContext) -> Option<Value> {
+        let addr = match ctx.try_extract_str(0, 1) {
+            Some(v) => v,
+            None => return Some(ErrorCode::InvalidInput.to_ret())
+        };
+
+        let app = ctx.app.upgrade().unwrap();
+
+        if let Err(code) = app.require_any_permission(&[
+            AppPermission::UdpBindAny,
+            AppPermission::UdpBind(addr.to_string())
+        ]) {
+            return Some(code.to_ret());
+        }
+
+        let saddr: SocketAddr = match addr.parse() {
+            Ok(v) => v,
+            Err(_) => return Some(ErrorCode::InvalidInput.to_ret())
+        };
+        let socket = match UdpSocket::bind(&saddr) {
+            Ok(v) => v,
+            Err(e) => {
+                derror!(
+                    logger!(&app.name),
+                    "Bind to {} failed: {:?}",
+                    addr,
+                    e
+                );
+                return Some(ErrorCode::BindFail.to_ret());
+            }
+        };
+
+        let socket_id = self.sockets.borrow_mut().insert(SocketEntry::new(socket));
+
+        Some(Value::I32(socket_id as i32))
+    }
+
+    /// Closes the socket. A pending send or receive fails with `Shutdown`.
+    pub fn destroy(&self, ctx: InvokeContext) -> Option<Value> {
+        let socket_id = ctx.args[0].get_i32().unwrap() as usize;
+
+        let mut sockets = self.sockets.borrow_mut();
+        if sockets.contains(socket_id) {
+            sockets.remove(socket_id);
+        }
+        None
+    }
+
+    pub fn release_buffer(&self, ctx: InvokeContext) -> Option<Value> {
+        self.buffers.borrow_mut().release_buffer(ctx)
+    }
+
+    pub fn take_buffer(&self, ctx: InvokeContext) -> Option<Value> {
+        self.buffers.borrow_mut().take_buffer(ctx)
+    }
+
+    pub fn buffer_len(&self, ctx: InvokeContext) -> Option<Value> {
+        self.buffers.borrow().buffer_len(ctx)
+    }
+
+    /// Calls back with `(cb_data, n_bytes_sent)`.
+    pub fn send_to(&self, ctx: InvokeContext) -> Option<Value> {
+        let socket_id = ctx.args[0].get_i32().unwrap() as usize;
+        let cb_target = ctx.args[5].get_i32().unwrap();
+        let cb_data = ctx.args[6].get_i32().unwrap();
+
+        let app = ctx.app.upgrade().unwrap();
+
+        let target: Option<SocketAddr> = ctx.try_extract_str(3, 4).and_then(|v| v.parse().ok());
+        let (data, target) = match (ctx.try_extract_bytes(1, 2), target) {
+            (Some(data), Some(target)) => (data, target),
+            _ => {
+                app.invoke2(
+                    cb_target,
+                    cb_data,
+                    ErrorCode::InvalidInput.to_i32()
+                );
+                return None;
+            }
+        };
+
+        let send_fut = match self.start_send(socket_id, data.to_vec(), target) {
+            Ok(v) => v,
+            Err(code) => {
+                app.invoke2(
+                    cb_target,
+                    cb_data,
+                    code.to_i32()
+                );
+                return None;
+            }
+        };
+
+        let app_weak = ctx.app.clone();
+
+        tokio::executor::current_thread::spawn(
+            send_fut.map(move |ret| {
+                if let Some(app) = app_weak.upgrade() {
+                    app.invoke2(
+                        cb_target,
+                        cb_data,
+                        ret
+                    );
+                }
+            })
+        );
+
+        None
+    }
+
+    /// Checks out `socket_id` for sending. The returned future resolves to
+    /// the number of bytes sent or a negative error code.
+    fn start_send(&self, socket_id: usize, data: Vec<u8>, target: SocketAddr) -> Result<Box<Future<Item = i32, Error = ()>>, ErrorCode> {
+        let (socket, cancel_rx) = match self.sockets.borrow_mut().get_mut(socket_id) {
+            Some(entry) => if entry.sending {
+                return Err(ErrorCode::OngoingIo);
+            } else {
+                let (cancel_tx, cancel_rx) = futures::sync::oneshot::channel::<()>();
+                entry.sending = true;
+                entry.send_cancel = Some(cancel_tx);
+                (entry.socket.clone(), cancel_rx)
+            },
+            None => return Err(ErrorCode::NotFound)
+        };
+
+        let sockets = self.sockets.clone();
+
+        Ok(Box::new(
+            SendToFuture {
+                socket: socket.clone(),
+                data: data,
+                target: target
+            }.select2(cancel_rx).then(move |result| -> Result<i32, ()> {
+                if let Some(entry) = entry_for(&mut sockets.borrow_mut(), socket_id, &socket) {
+                    entry.sending = false;
+                    entry.send_cancel = None;
+                }
+
+                Ok(match result {
+                    Ok(Either::A((n, _))) => n as i32,
+                    Err(Either::A((e, _))) => {
+                        derror!(logger!("(app)"), "Send error: {:?}", e);
+                        ErrorCode::from(e.kind()).to_i32()
+                    },
+                    // Destroyed while pending.
+                    Ok(Either::B(_)) | Err(Either::B(_)) => ErrorCode::Shutdown.to_i32()
+                })
+            })
+        ))
+    }
+
+    /// Calls back with `(cb_data, data_buffer_id, addr_buffer_id)`, where
+    /// the second buffer holds the sender address formatted as `ip:port`.
+    ///
+    /// On failure the second argument is a negative error code and the third
+    /// argument is unspecified.
+    pub fn recv_from(&self, ctx: InvokeContext) -> Option<Value> {
+        let socket_id = ctx.args[0].get_i32().unwrap() as usize;
+        let recv_len = ctx.args[1].get_i32().unwrap() as usize;
+        let cb_target = ctx.args[2].get_i32().unwrap();
+        let cb_data = ctx.args[3].get_i32().unwrap();
+
+        let recv_fut = match self.start_recv(socket_id, recv_len) {
+            Ok(v) => v,
+            Err(code) => {
+                ctx.app.upgrade().unwrap().invoke3(
+                    cb_target,
+                    cb_data,
+                    code.to_i32(),
+                    0
+                );
+                return None;
+            }
+        };
+
+        let app_weak = ctx.app.clone();
+
+        tokio::executor::current_thread::spawn(
+            recv_fut.map(move |(data_ret, addr_ret)| {
+                if let Some(app) = app_weak.upgrade() {
+                    app.invoke3(
+                        cb_target,
+                        cb_data,
+                        data_ret,
+                        addr_ret
+                    );
+                }
+            })
+        );
+
+        None
+    }
+
+    /// Checks out `socket_id` for receiving. The returned future resolves to
+    /// `(data_buffer_id, addr_buffer_id)`, or a negative error code and `0`.
+    fn start_recv(&self, socket_id: usize, recv_len: usize) -> Result<Box<Future<Item = (i32, i32), Error = ()>>, ErrorCode> {
+        let (socket, cancel_rx) = match self.sockets.borrow_mut().get_mut(socket_id) {
+            Some(entry) => if entry.receiving {
+                return Err(ErrorCode::OngoingIo);
+            } else {
+                let (cancel_tx, cancel_rx) = futures::sync::oneshot::channel::<()>();
+                entry.receiving = true;
+                entry.recv_cancel = Some(cancel_tx);
+                (entry.socket.clone(), cancel_rx)
+            },
+            None => return Err(ErrorCode::NotFound)
+        };
+
+        let sockets = self.sockets.clone();
+        let buffers = self.buffers.clone();
+
+        Ok(Box::new(
+            RecvFromFuture {
+                socket: socket.clone(),
+                buf: Some(self.buffers.borrow_mut().alloc(recv_len)),
+                len: recv_len
+            }.select2(cancel_rx).then(move |result| -> Result<(i32, i32), ()> {
+                if let Some(entry) = entry_for(&mut sockets.borrow_mut(), socket_id, &socket) {
+                    entry.receiving = false;
+                    entry.recv_cancel = None;
+                }
+
+                Ok(match result {
+                    Ok(Either::A(((data, len, addr), _))) => {
+                        let mut buffers = buffers.borrow_mut();
+                        let addr = format!("{}", addr).into_bytes();
+                        let addr_len = addr.len();
+
+                        (
+                            buffers.insert(data, len) as i32,
+                            buffers.insert(addr.into_boxed_slice(), addr_len) as i32
+                        )
+                    },
+                    Err(Either::A((e, _))) => {
+                        derror!(logger!("(app)"), "Receive error: {:?}", e);
+                        (ErrorCode::from(e.kind()).to_i32(), 0)
+                    },
+                    // Destroyed while pending.
+                    Ok(Either::B(_)) | Err(Either::B(_)) => (ErrorCode::Shutdown.to_i32(), 0)
+                })
+            })
+        ))
+    }
+}
+
+struct SendToFuture {
+    socket: Rc<RefCell<UdpSocket>>,
+    data: Vec<u8>,
+    target: SocketAddr
+}
+
+impl Future for SendToFuture {
+    type Item = usize;
+    type Error = tokio::io::Error;
+
+    fn poll(&mut self) -> futures::Poll<Self::Item, Self::Error> {
+        self.socket.borrow_mut().poll_send_to(&self.data, &self.target)
+    }
+}
+
+struct RecvFromFuture {
+    socket: Rc<RefCell<UdpSocket>>,
+    buf: Option<Box<[u8]>>,
+    len: usize
+}
+
+impl Future for RecvFromFuture {
+    type Item = (Box<[u8]>, usize, SocketAddr);
+    type Error = tokio::io::Error;
+
+    fn poll(&mut self) -> futures::Poll<Self::Item, Self::Error> {
+        let result = {
+            let buf = self.buf.as_mut().unwrap();
+            self.socket.borrow_mut().poll_recv_from(&mut buf[0..self.len])
+        };
+        match result {
+            Ok(futures::Async::Ready((n_bytes, addr))) => Ok(
+                futures::Async::Ready((self.buf.take().unwrap(), n_bytes, addr))
+            ),
+            Ok(futures::Async::NotReady) => Ok(futures::Async::NotReady),
+            Err(e) => Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use tokio::net::UdpSocket;
+    use tokio::runtime::current_thread::Runtime;
+    use super::super::super::error::ErrorCode;
+    use super::{UdpImpl, SocketEntry};
+
+    #[test]
+    fn destroy_cancels_pending_recv() {
+        let mut rt = Runtime::new().unwrap();
+        let udp = UdpImpl::new();
+
+        let socket = UdpSocket::bind(&"127.0.0.1:0".parse::<SocketAddr>().unwrap()).unwrap();
+        let addr = socket.local_addr().unwrap();
+        let socket_id = udp.sockets.borrow_mut().insert(SocketEntry::new(socket));
+
+        let recv_fut = udp.start_recv(socket_id, 1024).unwrap();
+        assert_eq!(udp.start_recv(socket_id, 1024).err(), Some(ErrorCode::OngoingIo));
+
+        udp.sockets.borrow_mut().remove(socket_id);
+        assert_eq!(rt.block_on(recv_fut), Ok((ErrorCode::Shutdown.to_i32(), 0)));
+
+        // The socket is closed, so its port can be bound again.
+        assert!(::std::net::UdpSocket::bind(addr).is_ok());
+    }
+
+    #[test]
+    fn cancelled_recv_leaves_reused_id_alone() {
+        let mut rt = Runtime::new().unwrap();
+        let udp = UdpImpl::new();
+        let any: SocketAddr = "127.0.0.1:0".parse().unwrap();
+
+        let socket_id = udp.sockets.borrow_mut().insert(SocketEntry::new(UdpSocket::bind(&any).unwrap()));
+        let recv_fut = udp.start_recv(socket_id, 1024).unwrap();
+        udp.sockets.borrow_mut().remove(socket_id);
+
+        assert_eq!(
+            udp.sockets.borrow_mut().insert(SocketEntry::new(UdpSocket::bind(&any).unwrap())),
+            socket_id
+        );
+        let _other_recv = udp.start_recv(socket_id, 1024).unwrap();
+
+        assert_eq!(rt.block_on(recv_fut), Ok((ErrorCode::Shutdown.to_i32(), 0)));
+        assert_eq!(udp.start_recv(socket_id, 1024).err(), Some(ErrorCode::OngoingIo));
+    }
+}
@@ -0,0 +1,504 @@
+use config::AppPermission;
+use super::super::namespace::InvokeContext;
+use super::super::error::ErrorCode;
+use super::super::app::ApplicationImpl;
+use super::tcp::{BufferPool, AsyncReadFuture, AsyncWriteAllFuture};
+use super::file::canonicalize_for_access;
+use wasm_core::value::Value;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::io::{Read, Write};
+use slab::Slab;
+
+use futures;
+use futures::{Future, Stream};
+use futures::future::Either;
+use tokio;
+use tokio::prelude::{AsyncRead, AsyncWrite};
+use tokio::net::{UnixListener, UnixStream};
+
+decl_namespace!(
+    UdsNs,
+    "uds",
+    UdsImpl,
+    release_buffer,
+    take_buffer,
+    buffer_len,
+    listen,
+    stop_listen,
+    connect,
+    read,
+    write,
+    destroy
+);
+
+/// Unix domain sockets, for local IPC with the host or sidecar processes.
+/// Mirrors the stream half of the tcp namespace.
+pub struct UdsImpl {
+    streams: Rc<RefCell<Slab<StreamEntry>>>,
+    buffers: Rc<RefCell<BufferPool>>,
+    listeners: RefCell<Slab<ListenerHandle>>
+}
+
+struct StreamEntry {
+    stream: SharedStream,
+    // Set while a read/write on this stream is pending.
+    reading: bool,
+    writing: bool,
+    // Dropped along with the entry on `destroy`, which cancels the
+    // pending read/write.
+    read_cancel: Option<futures::sync::oneshot::Sender<()>>,
+    write_cancel: Option<futures::sync::oneshot::Sender<()>>
+}
+
+impl StreamEntry {
+    fn new(stream: UnixStream) -> StreamEntry {
+        StreamEntry {
+            stream: SharedStream(Rc::new(stream)),
+            reading: false,
+            writing: false,
+            read_cancel: None,
+            write_cancel: None
+        }
+    }
+}
+
+/// Returns the entry at `stream_id` only if it still holds `stream`. After a
+/// `destroy`, the id may already belong to another stream.
+fn entry_for<'a>(
+    streams: &'a mut Slab<StreamEntry>,
+    stream_id: usize,
+    stream: &SharedStream
+) -> Option<&'a mut StreamEntry> {
+    streams.get_mut(stream_id).filter(|e| Rc::ptr_eq(&e.stream.0, &stream.0))
+}
+
+#[derive(Clone)]
+struct SharedStream(Rc<UnixStream>);
+
+impl Read for SharedStream {
+    fn read(&mut self, buf: &mut [u8]) -> ::std::io::Result<usize> {
+        (&*self.0).read(buf)
+    }
+}
+
+impl AsyncRead for SharedStream {}
+
+impl Write for SharedStream {
+    fn write(&mut self, buf: &[u8]) -> ::std::io::Result<usize> {
+        (&*self.0).write(buf)
+    }
+
+    fn flush(&mut self) -> ::std::io::Result<()> {
+        (&*self.0).flush()
+    }
+}
+
+impl AsyncWrite for SharedStream {
+    fn shutdown(&mut self) -> futures::Poll<(), ::std::io::Error> {
+        AsyncWrite::shutdown(&mut &*self.0)
+    }
+}
+
+struct ListenerHandle {
+    path: PathBuf,
+    // Dropping this sender terminates the accept loop.
+    _stop: futures::sync::oneshot::Sender<()>
+}
+
+impl Drop for ListenerHandle {
+    /// Removes the socket file so that the path can be bound again.
+    fn drop(&mut self) {
+        if let Err(e) = ::std::fs::remove_file(&self.path) {
+            dwarning!(
+                logger!("uds"),
+                "Unable to remove socket file {}: {:?}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+/// Resolves `path` and checks it against the app's `UnixSocket` prefixes.
+fn check_socket_path(app: &ApplicationImpl, path: &str) -> Result<PathBuf, ErrorCode> {
+    let canonical = canonicalize_for_access(::std::path::Path::new(path))
+        .map_err(|e| ErrorCode::from(e.kind()))?;
+    let canonical_str = canonical.to_str().ok_or(ErrorCode::InvalidInput)?.to_string();
+
+    app.require_any_permission(&[
+        AppPermission::UnixSocket(canonical_str)
+    ])?;
+
+    Ok(canonical)
+}
+
+impl UdsImpl {
+    pub fn new() -> UdsImpl {
+        UdsImpl {
+            streams: Rc::new(RefCell::new(Slab::new())),
+            buffers: Rc::new(RefCell::new(BufferPool::new())),
+            listeners: RefCell::new(Slab::new())
+        }
+    }
+
+    pub fn release_buffer(&self, ctx: InvokeContext) -> Option<Value> {
+        self.buffers.borrow_mut().release_buffer(ctx)
+    }
+
+    pub fn take_buffer(&self, ctx: InvokeContext) -> Option<Value> {
+        self.buffers.borrow_mut().take_buffer(ctx)
+    }
+
+    pub fn buffer_len(&self, ctx: InvokeContext) -> Option<Value> {
+        self.buffers.borrow().buffer_len(ctx)
+    }
+
+    /// `listen(path, cb_target, cb_data)`
+    ///
+    /// Calls back with `(cb_data, stream_id)` for each accepted connection
+    /// and returns the listener id. The socket file is removed by
+    /// `stop_listen`.
+    pub fn listen(&self, ctx: InvokeContext) -> Option<Value> {
+        let path = match ctx.try_extract_str(0, 1) {
+            Some(v) => v,
+            None => return Some(ErrorCode::InvalidInput.to_ret())
+        };
+        let cb_target = ctx.args[2].get_i32().unwrap();
+        let cb_data = ctx.args[3].get_i32().unwrap();
+
+        let app = ctx.app.upgrade().unwrap();
+
+        let path = match check_socket_path(&app, path) {
+            Ok(v) => v,
+            Err(code) => return Some(code.to_ret())
+        };
+
+        let listener = match UnixListener::bind(&path) {
+            Ok(v) => v,
+            Err(e) => {
+                derror!(
+                    logger!(&app.name),
+                    "Bind to {} failed: {:?}",
+                    path.display(),
+                    e
+                );
+                return Some(ErrorCode::BindFail.to_ret());
+            }
+        };
+
+        let streams = self.streams.clone();
+        let app_weak = ctx.app.clone();
+
+        let (stop_tx, stop_rx) = futures::sync::oneshot::channel::<()>();
+        let listener_id = self.listeners.borrow_mut().insert(ListenerHandle {
+            path: path,
+            _stop: stop_tx
+        });
+
+        tokio::executor::current_thread::spawn(
+            listener.incoming().for_each(move |stream| {
+                let stream_id = streams.borrow_mut().insert(StreamEntry::new(stream));
+
+                if let Some(app) = app_weak.upgrade() {
+                    app.invoke2(
+                        cb_target,
+                        cb_data,
+                        stream_id as _
+                    );
+                }
+                Ok(())
+            }).map_err(move |e| {
+                derror!(logger!("(app)"), "Accept error: {:?}", e);
+            }).select(
+                stop_rx.then(|_| Ok(()))
+            ).map(|_| ()).map_err(|_| ())
+        );
+
+        Some(Value::I32(listener_id as i32))
+    }
+
+    pub fn stop_listen(&self, ctx: InvokeContext) -> Option<Value> {
+        let listener_id = ctx.args[0].get_i32().unwrap() as usize;
+
+        let mut listeners = self.listeners.borrow_mut();
+        if !listeners.contains(listener_id) {
+            return Some(ErrorCode::NotFound.to_ret());
+        }
+        listeners.remove(listener_id);
+
+        Some(ErrorCode::Success.to_ret())
+    }
+
+    /// `connect(path, cb_target, cb_data)`
+    ///
+    /// Calls back with `(cb_data, stream_id)`, or a negative error code.
+    pub fn connect(&self, ctx: InvokeContext) -> Option<Value> {
+        let cb_target = ctx.args[2].get_i32().unwrap();
+        let cb_data = ctx.args[3].get_i32().unwrap();
+
+        let app = ctx.app.upgrade().unwrap();
+
+        let checked = match ctx.try_extract_str(0, 1) {
+            Some(path) => check_socket_path(&app, path),
+            None => Err(ErrorCode::InvalidInput)
+        };
+        let path = match checked {
+            Ok(v) => v,
+            Err(code) => {
+                app.invoke2(
+                    cb_target,
+                    cb_data,
+                    code.to_i32()
+                );
+                return None;
+            }
+        };
+
+        let streams = self.streams.clone();
+        let app_weak = ctx.app.clone();
+
+        tokio::executor::current_thread::spawn(
+            UnixStream::connect(&path).then(move |result| {
+                let ret = match result {
+                    Ok(stream) => streams.borrow_mut().insert(StreamEntry::new(stream)) as i32,
+                    Err(e) => {
+                        derror!(logger!("(app)"), "Connect error: {:?}", e);
+                        ErrorCode::from(e.kind()).to_i32()
+                    }
+                };
+                if let Some(app) = app_weak.upgrade() {
+                    app.invoke2(
+                        cb_target,
+                        cb_data,
+                        ret
+                    );
+                }
+                Ok(())
+            })
+        );
+
+        None
+    }
+
+    /// `read(stream_id, len, cb_target, cb_data)`
+    ///
+    /// Calls back with `(cb_data, buffer_id)`, or a negative error code.
+    /// A read still pending on `destroy` fails with `Shutdown`.
+    pub fn read(&self, ctx: InvokeContext) -> Option<Value> {
+        let stream_id = ctx.args[0].get_i32().unwrap() as usize;
+        let read_len = ctx.args[1].get_i32().unwrap() as usize;
+        let cb_target = ctx.args[2].get_i32().unwrap();
+        let cb_data = ctx.args[3].get_i32().unwrap();
+
+        let read_fut = match self.start_read(stream_id, read_len) {
+            Ok(v) => v,
+            Err(code) => {
+                ctx.app.upgrade().unwrap().invoke2(
+                    cb_target,
+                    cb_data,
+                    code.to_i32()
+                );
+                return None;
+            }
+        };
+
+        let app_weak = ctx.app.clone();
+
+        tokio::executor::current_thread::spawn(
+            read_fut.map(move |ret| {
+                if let Some(app) = app_weak.upgrade() {
+                    app.invoke2(
+                        cb_target,
+                        cb_data,
+                        ret
+                    );
+                }
+            })
+        );
+
+        None
+    }
+
+    /// Checks out `stream_id` for reading. The returned future resolves to
+    /// a buffer id or a negative error code.
+    fn start_read(&self, stream_id: usize, read_len: usize) -> Result<Box<Future<Item = i32, Error = ()>>, ErrorCode> {
+        let (conn, cancel_rx) = match self.streams.borrow_mut().get_mut(stream_id) {
+            Some(entry) => if entry.reading {
+                return Err(ErrorCode::OngoingIo);
+            } else {
+                let (cancel_tx, cancel_rx) = futures::sync::oneshot::channel::<()>();
+                entry.reading = true;
+                entry.read_cancel = Some(cancel_tx);
+                (entry.stream.clone(), cancel_rx)
+            },
+            None => return Err(ErrorCode::NotFound)
+        };
+
+        let streams = self.streams.clone();
+        let buffers = self.buffers.clone();
+
+        Ok(Box::new(
+            AsyncReadFuture::new(conn.clone(), read_len, &self.buffers).select2(cancel_rx).then(move |result| -> Result<i32, ()> {
+                if let Some(entry) = entry_for(&mut streams.borrow_mut(), stream_id, &conn) {
+                    entry.reading = false;
+                    entry.read_cancel = None;
+                }
+
+                Ok(match result {
+                    Ok(Either::A(((_, data, len), _))) => buffers.borrow_mut().insert(data, len) as i32,
+                    Err(Either::A((e, _))) => {
+                        derror!(logger!("(app)"), "Read error: {:?}", e);
+                        ErrorCode::from(e.kind()).to_i32()
+                    },
+                    // Destroyed while pending.
+                    Ok(Either::B(_)) | Err(Either::B(_)) => ErrorCode::Shutdown.to_i32()
+                })
+            })
+        ))
+    }
+
+    /// `write(stream_id, data_ptr, data_len, cb_target, cb_data)`
+    ///
+    /// Calls back with `(cb_data, ret, n_bytes_written)`, like `tcp_write`.
+    pub fn write(&self, ctx: InvokeContext) -> Option<Value> {
+        let stream_id = ctx.args[0].get_i32().unwrap() as usize;
+        let cb_target = ctx.args[3].get_i32().unwrap();
+        let cb_data = ctx.args[4].get_i32().unwrap();
+
+        let checkout = match ctx.try_extract_bytes(1, 2) {
+            Some(data) => self.start_write(stream_id, data.to_vec()),
+            None => Err(ErrorCode::InvalidInput)
+        };
+        let write_fut = match checkout {
+            Ok(v) => v,
+            Err(code) => {
+                ctx.app.upgrade().unwrap().invoke3(
+                    cb_target,
+                    cb_data,
+                    code.to_i32(),
+                    0
+                );
+                return None;
+            }
+        };
+
+        let app_weak = ctx.app.clone();
+
+        tokio::executor::current_thread::spawn(
+            write_fut.map(move |(ret, written)| {
+                if let Some(app) = app_weak.upgrade() {
+                    app.invoke3(
+                        cb_target,
+                        cb_data,
+                        ret,
+                        written as _
+                    );
+                }
+            })
+        );
+
+        None
+    }
+
+    /// Checks out `stream_id` for writing. The returned future resolves to
+    /// `(ret, n_bytes_written)`.
+    fn start_write(&self, stream_id: usize, data: Vec<u8>) -> Result<Box<Future<Item = (i32, usize), Error = ()>>, ErrorCode> {
+        let (conn, cancel_rx) = match self.streams.borrow_mut().get_mut(stream_id) {
+            Some(entry) => if entry.writing {
+                return Err(ErrorCode::OngoingIo);
+            } else {
+                let (cancel_tx, cancel_rx) = futures::sync::oneshot::channel::<()>();
+                entry.writing = true;
+                entry.write_cancel = Some(cancel_tx);
+                (entry.stream.clone(), cancel_rx)
+            },
+            None => return Err(ErrorCode::NotFound)
+        };
+
+        let streams = self.streams.clone();
+        let data_len = data.len();
+
+        Ok(Box::new(
+            AsyncWriteAllFuture::new(conn.clone(), data).select2(cancel_rx).then(move |result| -> Result<(i32, usize), ()> {
+                if let Some(entry) = entry_for(&mut streams.borrow_mut(), stream_id, &conn) {
+                    entry.writing = false;
+                    entry.write_cancel = None;
+                }
+
+                Ok(match result {
+                    Ok(Either::A((n, _))) => (data_len as i32, n),
+                    Err(Either::A(((e, n), _))) => {
+                        derror!(logger!("(app)"), "Write error: {:?}", e);
+                        (ErrorCode::from(e.kind()).to_i32(), n)
+                    },
+                    // Destroyed while pending.
+                    Ok(Either::B(_)) | Err(Either::B(_)) => (ErrorCode::Shutdown.to_i32(), 0)
+                })
+            })
+        ))
+    }
+
+    /// Closes the stream. Pending reads and writes fail with `Shutdown`.
+    pub fn destroy(&self, ctx: InvokeContext) -> Option<Value> {
+        let stream_id = ctx.args[0].get_i32().unwrap() as usize;
+
+        let mut streams = self.streams.borrow_mut();
+        if streams.contains(stream_id) {
+            streams.remove(stream_id);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio;
+    use tokio::net::UnixStream;
+    use tokio::runtime::current_thread::Runtime;
+    use super::super::super::error::ErrorCode;
+    use super::{UdsImpl, StreamEntry};
+
+    #[test]
+    fn destroy_cancels_pending_read() {
+        let mut rt = Runtime::new().unwrap();
+        let uds = UdsImpl::new();
+
+        let (local, peer) = UnixStream::pair().unwrap();
+        let stream_id = uds.streams.borrow_mut().insert(StreamEntry::new(local));
+
+        let read_fut = uds.start_read(stream_id, 16).unwrap();
+        assert_eq!(uds.start_read(stream_id, 16).err(), Some(ErrorCode::OngoingIo));
+
+        uds.streams.borrow_mut().remove(stream_id);
+
+        // The id is reused by the next stream, which the cancelled read
+        // must leave alone.
+        let (other, _other_peer) = UnixStream::pair().unwrap();
+        assert_eq!(uds.streams.borrow_mut().insert(StreamEntry::new(other)), stream_id);
+        let _other_read = uds.start_read(stream_id, 16).unwrap();
+
+        assert_eq!(rt.block_on(read_fut), Ok(ErrorCode::Shutdown.to_i32()));
+        assert_eq!(uds.start_read(stream_id, 16).err(), Some(ErrorCode::OngoingIo));
+
+        // The destroyed stream is closed, so the peer sees EOF.
+        let (_, data) = rt.block_on(tokio::io::read_to_end(peer, Vec::new())).unwrap();
+        assert!(data.is_empty());
+    }
+
+    #[test]
+    fn destroy_cancels_pending_write() {
+        let mut rt = Runtime::new().unwrap();
+        let uds = UdsImpl::new();
+
+        // The peer never reads, so a write larger than the socket buffers
+        // stays pending.
+        let (local, _peer) = UnixStream::pair().unwrap();
+        let stream_id = uds.streams.borrow_mut().insert(StreamEntry::new(local));
+
+        let write_fut = uds.start_write(stream_id, vec! [ 0; 16 * 1024 * 1024 ]).unwrap();
+        uds.streams.borrow_mut().remove(stream_id);
+        assert_eq!(rt.block_on(write_fut), Ok((ErrorCode::Shutdown.to_i32(), 0)));
+    }
+}
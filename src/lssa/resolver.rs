@@ -2,7 +2,9 @@ use wasm_core::executor::{NativeResolver, NativeEntry};
 use super::app::ApplicationImpl;
 use std::rc::Weak;
 use std::collections::BTreeMap;
-use super::namespace::Namespace;
+use super::namespace::{Namespace, CustomNamespace, CustomNamespaceDef};
+use std::sync::Arc;
+use container::Container;
 
 pub struct LssaResolver {
     app: Weak<ApplicationImpl>,
@@ -54,12 +56,29 @@ impl LssaResolver {
         self.namespaces.insert(prefix, Box::new(ns));
     }
 
-    pub fn init_default_namespaces(&mut self) {
+    /// Adds the namespaces registered by the embedder. A custom namespace
+    /// never replaces a built-in one.
+    pub fn init_custom_namespaces(&mut self, defs: &[Arc<CustomNamespaceDef>]) {
+        for def in defs {
+            if self.namespaces.contains_key(def.prefix()) {
+                dwarning!(
+                    logger!("LssaResolver::init_custom_namespaces"),
+                    "Namespace {} is built in, ignoring the custom one",
+                    def.prefix()
+                );
+                continue;
+            }
+            let ns = CustomNamespace::new(def.clone(), self.app.clone());
+            self.add_namespace(ns);
+        }
+    }
+
+    pub fn init_default_namespaces(&mut self, container: &Container) {
         use super::ns;
         let app = self.app.clone();
 
         self.add_namespace(ns::timer::TimerNs::new(
-            ns::timer::TimerImpl,
+            ns::timer::TimerImpl::new(),
             app.clone()
         ));
         self.add_namespace(ns::logging::LoggingNs::new(
@@ -71,8 +90,77 @@ impl LssaResolver {
             app.clone()
         ));
         self.add_namespace(ns::file::FileNs::new(
-            ns::file::FileImpl::new(),
+            ns::file::FileImpl::new(container.blocking_pool()),
+            app.clone()
+        ));
+        self.add_namespace(ns::udp::UdpNs::new(
+            ns::udp::UdpImpl::new(),
+            app.clone()
+        ));
+        self.add_namespace(ns::http_client::HttpClientNs::new(
+            ns::http_client::HttpClientImpl::new(),
+            app.clone()
+        ));
+        self.add_namespace(ns::random::RandomNs::new(
+            ns::random::RandomImpl,
+            app.clone()
+        ));
+        self.add_namespace(ns::env::EnvNs::new(
+            ns::env::EnvImpl::new(),
+            app.clone()
+        ));
+        self.add_namespace(ns::msg::MsgNs::new(
+            ns::msg::MsgImpl,
             app.clone()
         ));
+        #[cfg(unix)]
+        self.add_namespace(ns::uds::UdsNs::new(
+            ns::uds::UdsImpl::new(),
+            app.clone()
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Weak;
+    use std::sync::Arc;
+    use wasm_core::executor::NativeResolver;
+    use wasm_core::value::Value;
+    use super::super::namespace::CustomNamespaceDef;
+    use super::super::testutil::{TestState, config_with_apps};
+    use container::Container;
+    use super::LssaResolver;
+
+    fn add_one(prefix: &str) -> Arc<CustomNamespaceDef> {
+        Arc::new(CustomNamespaceDef::new(prefix).function("add1", |ctx| {
+            Some(Value::I32(ctx.args[0].get_i32().unwrap() + 1))
+        }))
+    }
+
+    #[test]
+    fn resolves_custom_namespace() {
+        let mut resolver = LssaResolver::new(Weak::new());
+        resolver.init_custom_namespaces(&[add_one("demo")]);
+
+        let f = resolver.resolve("env", "__ice_demo_add1").unwrap();
+        let mut state = TestState::new(0);
+        match f(&mut state, &[Value::I32(41)]) {
+            Ok(Some(Value::I32(42))) => {},
+            _ => panic!("unexpected result")
+        }
+
+        assert!(resolver.resolve("env", "__ice_demo_missing").is_none());
+        assert!(resolver.resolve("other", "__ice_demo_add1").is_none());
+    }
+
+    #[test]
+    fn builtin_namespace_wins_over_custom() {
+        let mut resolver = LssaResolver::new(Weak::new());
+        resolver.init_default_namespaces(&Container::new(config_with_apps(&[])));
+        resolver.init_custom_namespaces(&[add_one("timer")]);
+
+        assert!(resolver.resolve("env", "__ice_timer_add1").is_none());
+        assert!(resolver.resolve("env", "__ice_timer_set_timeout").is_some());
     }
 }
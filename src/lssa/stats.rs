@@ -1,5 +1,6 @@
 use std::collections::BTreeMap;
 use futures::sync::mpsc::Sender;
+use super::metrics::MetricsSnapshot;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Stats {
@@ -9,7 +10,9 @@ pub struct Stats {
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct AppStats {
     pub start_time: i64,
-    pub running_time: i64
+    pub running_time: i64,
+    #[serde(default)]
+    pub metrics: Option<MetricsSnapshot>
 }
 
 pub struct StatsRequest {
@@ -0,0 +1,173 @@
+//! Helpers for tests that need a running app.
+
+use wasm_core::trans;
+use wasm_core::executor::{GlobalStateProvider, NativeEntry};
+use wasm_core::value::Value;
+
+use config::{Config, ApplicationConfig, AppMetadata, AppPermission};
+use container::Container;
+use super::app::Application;
+use super::namespace::InvokeContext;
+
+/// `unreachable; end`
+pub const TRAP: &[u8] = &[0x00, 0x0b];
+
+/// `i32.const 0; end`
+pub const RETURN_ZERO: &[u8] = &[0x41, 0x00, 0x0b];
+
+/// `i32.const 1; end`
+pub const RETURN_ONE: &[u8] = &[0x41, 0x01, 0x0b];
+
+/// Builds a minimal app module exporting `__app_init` and
+/// `__app_invoke0` to `__app_invoke4`. `init` and `invoke` are the code of
+/// the function bodies, without locals.
+pub fn app_module(init: &[u8], invoke: &[u8]) -> Vec<u8> {
+    app_module_with(init, &[invoke; 5])
+}
+
+/// Like `app_module`, with a separate body for each `__app_invokeN`.
+pub fn app_module_with(init: &[u8], invoke: &[&[u8]; 5]) -> Vec<u8> {
+    fn section(out: &mut Vec<u8>, id: u8, payload: &[u8]) {
+        // Keeps the LEB128 sizes down to a single byte.
+        assert!(payload.len() < 128);
+        out.push(id);
+        out.push(payload.len() as u8);
+        out.extend_from_slice(payload);
+    }
+
+    let mut out: Vec<u8> = vec! [ 0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00 ];
+
+    // Type `n` takes `n` i32 params and returns an i32.
+    let mut types = vec! [ 6 ];
+    for n in 0..6 {
+        types.extend_from_slice(&[0x60, n]);
+        for _ in 0..n {
+            types.push(0x7f);
+        }
+        types.extend_from_slice(&[0x01, 0x7f]);
+    }
+    section(&mut out, 1, &types);
+
+    // `__app_init` has type 0, `__app_invokeN` has type N + 1.
+    section(&mut out, 3, &[6, 0, 1, 2, 3, 4, 5]);
+
+    let mut exports = vec! [ 6 ];
+    let names: Vec<String> = Some("__app_init".to_string()).into_iter()
+        .chain((0..5).map(|n| format!("__app_invoke{}", n)))
+        .collect();
+    for (i, name) in names.iter().enumerate() {
+        exports.push(name.len() as u8);
+        exports.extend_from_slice(name.as_bytes());
+        exports.extend_from_slice(&[0x00, i as u8]);
+    }
+    section(&mut out, 7, &exports);
+
+    let mut code = vec! [ 6 ];
+    for i in 0..6 {
+        let body = if i == 0 { init } else { invoke[i - 1] };
+        code.push(body.len() as u8 + 1);
+        code.push(0x00);
+        code.extend_from_slice(body);
+    }
+    section(&mut out, 10, &code);
+
+    out
+}
+
+pub fn app_config(name: &str) -> ApplicationConfig {
+    ApplicationConfig {
+        name: name.to_string(),
+        path: "/nonexistent".to_string(),
+        memory: Default::default(),
+        optimize: false,
+        collect_metrics: false,
+        allowed_env: Vec::new(),
+        metadata: AppMetadata {
+            package_name: name.to_string(),
+            permissions: Default::default(),
+            bin: "app.wasm".to_string()
+        }
+    }
+}
+
+pub fn config_with_apps(names: &[&str]) -> Config {
+    Config {
+        applications: names.iter().map(|v| app_config(v)).collect(),
+        services: Vec::new()
+    }
+}
+
+/// Compiles `code` as the app `name`, which must be part of the config
+/// of `container`. The app is not initialized.
+pub fn load_app(container: &Container, name: &str, code: &[u8]) -> Application {
+    Application::new(
+        trans::translate_module_raw(code, Default::default()),
+        app_config(name),
+        container.clone()
+    )
+}
+
+/// Loads and initializes an app whose callbacks do nothing, holding
+/// `permissions`.
+pub fn idle_app(name: &str, permissions: &[AppPermission]) -> (Container, Application) {
+    let mut config = config_with_apps(&[name]);
+    config.applications[0].metadata.permissions = permissions.iter().cloned().collect();
+
+    let container = Container::new(config);
+    let app = load_app(&container, name, &app_module(RETURN_ZERO, RETURN_ZERO));
+    app.initialize(None).unwrap();
+    (container, app)
+}
+
+/// Loads and initializes an app that only accepts callbacks taking
+/// `arity` arguments besides the target. Calls with any other arity trap
+/// and fault the app, like a `call_indirect` signature mismatch would.
+pub fn callback_app(name: &str, arity: usize) -> (Container, Application) {
+    let mut invoke = [TRAP; 5];
+    invoke[arity] = RETURN_ZERO;
+
+    let container = Container::new(config_with_apps(&[name]));
+    let app = load_app(&container, name, &app_module_with(RETURN_ZERO, &invoke));
+    app.initialize(None).unwrap();
+    (container, app)
+}
+
+/// Stands in for app memory when calling namespace functions directly.
+pub struct TestState {
+    pub memory: Vec<u8>
+}
+
+impl TestState {
+    pub fn new(len: usize) -> TestState {
+        TestState {
+            memory: vec! [ 0; len ]
+        }
+    }
+}
+
+impl GlobalStateProvider for TestState {
+    fn get_memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    fn get_memory_mut(&mut self) -> &mut [u8] {
+        &mut self.memory
+    }
+
+    fn resolve(&self, _module: &str, _field: &str) -> Option<NativeEntry> {
+        None
+    }
+}
+
+/// Calls the namespace function `f` on behalf of `app`, with `state` as
+/// the app memory.
+pub fn call<F>(app: &Application, state: &mut TestState, args: &[Value], f: F) -> Option<Value>
+    where F: FnOnce(InvokeContext) -> Option<Value>
+{
+    let app = app.downgrade();
+    f(InvokeContext {
+        state: state,
+        args: args,
+        app: &app
+    })
+}
@@ -1,30 +1,12 @@
-#![feature(nll)]
-
-extern crate wasm_core;
-extern crate serde;
 #[macro_use]
-extern crate serde_derive;
-extern crate serde_yaml;
-extern crate slab;
+extern crate ice_core;
 extern crate futures;
 extern crate tokio;
-extern crate ansi_term;
-extern crate chrono;
-extern crate tokio_io;
-
-#[macro_use]
-mod logging;
-
-#[macro_use]
-mod lssa;
-
-mod container;
-mod config;
-mod server;
 
 use std::panic::catch_unwind;
-use config::Config;
-use server::Server;
+use futures::Future;
+use ice_core::config::Config;
+use ice_core::Server;
 
 fn main() {
     let config_path = ::std::env::args().nth(1).unwrap_or_else(|| {
@@ -45,9 +27,19 @@ fn main() {
         }
     };
 
+    let problems = config.validate();
+    if !problems.is_empty() {
+        for p in &problems {
+            derror!(logger!("(main)"), "{}", p);
+        }
+        ::std::process::exit(1);
+    }
+
     let server = Server::new(config);
 
-    tokio::executor::current_thread::block_on_all(
+    tokio::runtime::current_thread::block_on_all(
         server.run_apps()
+            .join(server.watch_config(config_path))
+            .map(|_| ())
     ).unwrap();
 }
@@ -1,29 +1,44 @@
 use container::{Container, ControlDispatcher};
-use config::Config;
+use config::{Config, ServiceConfig};
 use lssa::control::Control;
 use lssa::manager::AppManager;
+use lssa::namespace::CustomNamespaceDef;
+use services::{start_builtin_service, ServiceHandle};
+
+use std::panic::catch_unwind;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
 use futures;
 use futures::Future;
 use futures::Stream;
 use futures::Sink;
 //use futures::{StreamExt, FutureExt};
+use tokio;
+
+/// How often `watch_config` checks the config file for changes.
+const CONFIG_POLL_INTERVAL_MS: u64 = 2000;
 
+#[derive(Clone)]
 pub struct Server {
-    container: Container
+    container: Container,
+    services: Arc<Mutex<Vec<(ServiceConfig, ServiceHandle)>>>
 }
 
 impl Server {
     pub fn new(config: Config) -> Server {
         Server {
-            container: Container::new(config)
+            container: Container::new(config),
+            services: Arc::new(Mutex::new(Vec::new()))
         }
     }
 
     fn launch_manager(container: Container) -> futures::sync::mpsc::Sender<Control> {
         let (tx, rx) = futures::sync::mpsc::channel(4096);
         ::std::thread::spawn(move || {
-            ::tokio::executor::current_thread::block_on_all(
+            // The runtime (unlike a bare executor) also drives the timer
+            // used by namespaces.
+            ::tokio::runtime::current_thread::block_on_all(
                 futures::future::ok(()).map(move |_| {
                     let mut manager = AppManager::new(container.clone());
                     load_apps_from_config(
@@ -31,7 +46,7 @@ impl Server {
                         &container.config_state.read().unwrap().config
                     );
                     manager
-                }).map(move |manager| {
+                }).map(move |mut manager| {
                     rx.for_each(move |c| {
                         manager.dispatch_control(c);
                         Ok(())
@@ -42,14 +57,111 @@ impl Server {
         tx
     }
 
+    /// Makes the host functions in `def` available to apps. Must be called
+    /// before `run_apps` for the initial apps to see them; apps loaded by a
+    /// later config reload see every namespace registered so far.
+    pub fn register_namespace(&self, def: CustomNamespaceDef) -> Result<(), ()> {
+        self.container.register_namespace(def)
+    }
+
+    /// Switches the running server to `config`. Only apps whose `path` or
+    /// `memory` changed are restarted, and only built-in services whose
+    /// config changed are rebound. Must be called from within the executor
+    /// running `run_apps`.
+    pub fn reload_config(&self, config: Config) -> Result<(), ()> {
+        let problems = config.validate();
+        if !problems.is_empty() {
+            for p in &problems {
+                derror!(logger!("Server::reload_config"), "{}", p);
+            }
+            return Err(());
+        }
+
+        let services = config.services.clone();
+        self.container.dispatch_control(Control::ReloadConfig(config))?;
+        tokio::executor::current_thread::spawn(self.restart_services(services));
+        Ok(())
+    }
+
+    /// Stops the running built-in services that aren't in `services` and
+    /// starts the new ones. Old listeners are closed before anything is
+    /// bound, so a service can move to an address another one just left.
+    fn restart_services(&self, services: Vec<ServiceConfig>) -> impl Future<Item = (), Error = ()> {
+        let mut stopping = Vec::new();
+        {
+            let mut running = self.services.lock().unwrap();
+            let old = ::std::mem::replace(&mut *running, Vec::new());
+            for (config, handle) in old {
+                if services.contains(&config) {
+                    running.push((config, handle));
+                } else {
+                    stopping.push(handle.stop());
+                }
+            }
+        }
+
+        let server = self.clone();
+        futures::future::join_all(stopping).map(move |_| {
+            server.start_services(&services);
+        })
+    }
+
+    /// Starts each service in `services` that isn't already running.
+    fn start_services(&self, services: &[ServiceConfig]) {
+        let mut running = self.services.lock().unwrap();
+        for service in services {
+            if running.iter().any(|&(ref config, _)| config == service) {
+                continue;
+            }
+            if let Some(handle) = start_builtin_service(&self.container, service) {
+                running.push((service.clone(), handle));
+            }
+        }
+    }
+
+    /// Reloads the config from `path` whenever the file's modification time
+    /// changes.
+    pub fn watch_config(&self, path: String) -> impl Future<Item = (), Error = ()> {
+        let server = self.clone();
+        let interval = Duration::from_millis(CONFIG_POLL_INTERVAL_MS);
+        let mut last_modified = config_mtime(&path);
+
+        tokio::timer::Interval::new(Instant::now() + interval, interval)
+            .map_err(|e| {
+                derror!(logger!("Server::watch_config"), "Timer error: {:?}", e);
+            })
+            .for_each(move |_| {
+                let modified = config_mtime(&path);
+                if modified == last_modified {
+                    return Ok(());
+                }
+                last_modified = modified;
+
+                dinfo!(logger!("Server::watch_config"), "Config file changed. Reloading.");
+                match catch_unwind(|| Config::from_file(&path)) {
+                    Ok(config) => {
+                        let _ = server.reload_config(config);
+                    },
+                    Err(_) => {
+                        derror!(logger!("Server::watch_config"), "Invalid config file");
+                    }
+                }
+                Ok(())
+            })
+    }
+
     pub fn run_apps(&self) -> impl Future<Item = (), Error = ()> {
         let (tx, rx) = futures::sync::mpsc::channel::<Control>(4096);
         self.container.set_control_dispatcher(ControlDispatcher::new(tx));
 
         let container = self.container.clone();
-        let mut control_sender = Self::launch_manager(container);
+        let mut control_sender = Self::launch_manager(container.clone());
+        let server = self.clone();
 
         futures::future::ok(()).then(move |_: Result<(), ()>| {
+            let services = container.config_state.read().unwrap().config.services.clone();
+            server.start_services(&services);
+
             rx.for_each(move |c| {
                 control_sender.start_send(c).unwrap();
                 Ok(())
@@ -58,30 +170,56 @@ impl Server {
     }
 }
 
-fn load_apps_from_config(manager: &mut AppManager, config: &Config) {
-    use std::fs::File;
-    use std::io::Read;
+fn config_mtime(path: &str) -> Option<SystemTime> {
+    ::std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
 
+fn load_apps_from_config(manager: &mut AppManager, config: &Config) {
     for (i, app) in config.applications.iter().enumerate() {
-        let mut code_file = match File::open(
-            &::std::path::Path::new(
-                &app.path
-            ).join(&app.metadata.bin).to_str().unwrap()
-        ) {
-            Ok(v) => v,
-            Err(e) => {
-                dwarning!(
-                    logger!("load_apps_from_config"),
-                    "Unable to load app `{}`: {:?}",
-                    app.name,
-                    e
-                );
-                continue;
-            }
-        };
-        let mut code: Vec<u8> = Vec::new();
-        code_file.read_to_end(&mut code).unwrap();
+        manager.load_from_config(i, app);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, TcpStream};
+    use futures;
+    use tokio::runtime::current_thread::Runtime;
+    use config::{ServiceConfig, ServiceKind};
+    use lssa::testutil::config_with_apps;
+    use super::Server;
+
+    fn free_addr() -> String {
+        TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string()
+    }
+
+    fn health_on(addr: &str) -> ServiceConfig {
+        ServiceConfig {
+            kind: ServiceKind::Health,
+            listen_addr: addr.to_string(),
+            max_connections: None
+        }
+    }
+
+    #[test]
+    fn restart_services_rebinds_changed_services() {
+        let mut rt = Runtime::new().unwrap();
+        let server = Server::new(config_with_apps(&[]));
+        let (kept, old, new) = (free_addr(), free_addr(), free_addr());
+
+        rt.block_on(futures::future::lazy(|| {
+            server.start_services(&[health_on(&kept), health_on(&old)]);
+            Ok::<(), ()>(())
+        })).unwrap();
+        assert!(TcpStream::connect(&old).is_ok());
+
+        rt.block_on(futures::future::lazy(|| {
+            server.restart_services(vec! [ health_on(&kept), health_on(&new) ])
+        })).unwrap();
 
-        manager.load(&code, i, app.clone());
+        assert!(TcpStream::connect(&kept).is_ok());
+        assert!(TcpStream::connect(&old).is_err());
+        assert!(TcpStream::connect(&new).is_ok());
+        assert_eq!(server.services.lock().unwrap().len(), 2);
     }
 }
@@ -0,0 +1,256 @@
+use std::net::SocketAddr;
+use std::fmt::Write;
+use std::time::Duration;
+
+use container::Container;
+use config::{ServiceConfig, ServiceKind};
+use lssa::control::Control;
+use lssa::stats::{Stats, StatsRequest};
+
+use futures;
+use futures::{Future, Stream};
+use tokio;
+use tokio::net::{TcpListener, TcpStream};
+
+/// A running built-in service. Dropping it stops the service.
+pub struct ServiceHandle {
+    stop: futures::sync::oneshot::Sender<()>,
+    stopped: futures::sync::oneshot::Receiver<()>
+}
+
+impl ServiceHandle {
+    /// Stops the service. Resolves once its listener is closed, so that
+    /// the address can be bound again.
+    pub fn stop(self) -> impl Future<Item = (), Error = ()> {
+        drop(self.stop);
+        self.stopped.then(|_| Ok(()))
+    }
+}
+
+/// Starts `service` if it is a built-in service (one that doesn't need an
+/// app). Must be called from within an executor.
+pub fn start_builtin_service(container: &Container, service: &ServiceConfig) -> Option<ServiceHandle> {
+    match service.kind {
+        ServiceKind::Metrics => serve_tcp(container, service, serve_metrics),
+        ServiceKind::Health => serve_tcp(container, service, serve_health),
+        _ => None
+    }
+}
+
+fn serve_tcp<F, R>(container: &Container, service: &ServiceConfig, handler: F) -> Option<ServiceHandle>
+    where F: Fn(Container, TcpStream) -> R + 'static,
+          R: Future<Item = (), Error = ()> + 'static
+{
+    let logger = logger!("start_builtin_service");
+
+    let addr: SocketAddr = service.listen_addr.parse().unwrap();
+    let listener = match TcpListener::bind(&addr) {
+        Ok(v) => v,
+        Err(e) => {
+            derror!(logger, "Unable to bind to {}: {:?}", addr, e);
+            return None;
+        }
+    };
+    dinfo!(logger, "Listening on {}", addr);
+
+    let container = container.clone();
+    let incoming = listener.incoming();
+
+    let serve: Box<Future<Item = (), Error = ::std::io::Error>> = match service.max_connections {
+        // Connections past the limit aren't accepted until one finishes.
+        Some(n) => Box::new(
+            incoming
+                .map(move |stream| handler(container.clone(), stream).then(|_| Ok(())))
+                .buffer_unordered(n)
+                .for_each(|_| Ok(()))
+        ),
+        None => Box::new(
+            incoming.for_each(move |stream| {
+                tokio::executor::current_thread::spawn(handler(container.clone(), stream));
+                Ok(())
+            })
+        )
+    };
+
+    let (stop_tx, stop_rx) = futures::sync::oneshot::channel::<()>();
+    let (stopped_tx, stopped_rx) = futures::sync::oneshot::channel::<()>();
+
+    let serve = serve.map_err(|e| {
+        derror!(logger!("serve_tcp"), "Accept error: {:?}", e);
+    });
+    tokio::executor::current_thread::spawn(serve.select2(stop_rx).then(move |result| {
+        // Drops the listener before reporting that the service stopped.
+        drop(result);
+        dinfo!(logger!("serve_tcp"), "Stopped listening on {}", addr);
+        drop(stopped_tx);
+        Ok(())
+    }));
+
+    Some(ServiceHandle {
+        stop: stop_tx,
+        stopped: stopped_rx
+    })
+}
+
+/// How long to wait for the app manager before answering `503`. Keeps a
+/// stuck or busy manager from hanging health checks.
+const STATS_TIMEOUT_MS: u64 = 3000;
+
+/// Asks the app manager for a stats snapshot. Fails if it doesn't answer
+/// within `STATS_TIMEOUT_MS`.
+fn fetch_stats(container: &Container) -> impl Future<Item = Stats, Error = ()> {
+    let (tx, rx) = futures::sync::mpsc::channel(1);
+    let dispatched = container.dispatch_control(Control::Stats(StatsRequest {
+        feedback: tx
+    }));
+
+    futures::future::result(dispatched).and_then(|_| {
+        let stats = rx.into_future()
+            .map_err(|_| ())
+            .and_then(|(stats, _)| stats.ok_or(()));
+        tokio::timer::Timeout::new(stats, Duration::from_millis(STATS_TIMEOUT_MS))
+            .map_err(|_| ())
+    })
+}
+
+/// Reads (and ignores) the request head, then writes a complete HTTP/1.0
+/// response produced by `body`.
+fn respond<F, B>(stream: TcpStream, body: F) -> impl Future<Item = (), Error = ()>
+    where F: FnOnce() -> B,
+          B: Future<Item = (String, &'static str, String), Error = ()>
+{
+    tokio::io::read(stream, vec! [ 0; 4096 ])
+        .map_err(|_| ())
+        .and_then(move |(stream, _, _)| {
+            body().map(move |v| (stream, v))
+        })
+        .and_then(|(stream, (status, content_type, body))| {
+            let resp = format!(
+                "HTTP/1.0 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                content_type,
+                body.len(),
+                body
+            );
+            tokio::io::write_all(stream, resp.into_bytes()).map_err(|_| ())
+        })
+        .map(|_| ())
+}
+
+fn serve_metrics(container: Container, stream: TcpStream) -> impl Future<Item = (), Error = ()> {
+    respond(stream, move || {
+        fetch_stats(&container).then(|result| Ok(match result {
+            Ok(stats) => (
+                "200 OK".to_string(),
+                "text/plain; version=0.0.4",
+                render_prometheus(&stats)
+            ),
+            Err(_) => (
+                "503 Service Unavailable".to_string(),
+                "text/plain",
+                "stats unavailable\n".to_string()
+            )
+        }))
+    })
+}
+
+/// Responds `200 OK` while the app manager is answering, with the number of
+/// running and configured apps, and `503` once it isn't.
+fn serve_health(container: Container, stream: TcpStream) -> impl Future<Item = (), Error = ()> {
+    respond(stream, move || {
+        let n_configured = container.config_state.read().unwrap().config.applications.len();
+
+        fetch_stats(&container).then(move |result| Ok(match result {
+            Ok(stats) => (
+                "200 OK".to_string(),
+                "text/plain",
+                format!(
+                    "ok\napps_running {}\napps_configured {}\n",
+                    stats.applications.len(),
+                    n_configured
+                )
+            ),
+            Err(_) => (
+                "503 Service Unavailable".to_string(),
+                "text/plain",
+                "unavailable\n".to_string()
+            )
+        }))
+    })
+}
+
+/// Renders `stats` in the Prometheus text exposition format.
+fn render_prometheus(stats: &Stats) -> String {
+    let mut out = String::new();
+
+    write_header(&mut out, "ice_app_start_time_ms", "gauge", "Time the app was started, in milliseconds since the Unix epoch.");
+    for (name, app) in &stats.applications {
+        writeln!(out, "ice_app_start_time_ms{{app=\"{}\"}} {}", escape_label(name), app.start_time).unwrap();
+    }
+
+    write_header(&mut out, "ice_app_running_time_ms", "gauge", "Time since the app was started, in milliseconds.");
+    for (name, app) in &stats.applications {
+        writeln!(out, "ice_app_running_time_ms{{app=\"{}\"}} {}", escape_label(name), app.running_time).unwrap();
+    }
+
+    write_header(&mut out, "ice_namespace_calls_total", "counter", "Number of calls into each namespace function.");
+    for (name, app) in &stats.applications {
+        if let Some(ref m) = app.metrics {
+            for (f, count) in &m.calls {
+                writeln!(
+                    out,
+                    "ice_namespace_calls_total{{app=\"{}\",function=\"{}\"}} {}",
+                    escape_label(name),
+                    escape_label(f),
+                    count
+                ).unwrap();
+            }
+        }
+    }
+
+    write_header(&mut out, "ice_async_op_latency_microseconds", "summary", "Latency of async operations, in microseconds.");
+    for (name, app) in &stats.applications {
+        if let Some(ref m) = app.metrics {
+            for (op, l) in &m.latency {
+                let labels = format!("app=\"{}\",op=\"{}\"", escape_label(name), escape_label(op));
+                writeln!(out, "ice_async_op_latency_microseconds_sum{{{}}} {}", labels, l.total_micros).unwrap();
+                writeln!(out, "ice_async_op_latency_microseconds_count{{{}}} {}", labels, l.count).unwrap();
+            }
+        }
+    }
+
+    write_header(&mut out, "ice_async_op_latency_max_microseconds", "gauge", "Maximum latency of async operations, in microseconds.");
+    for (name, app) in &stats.applications {
+        if let Some(ref m) = app.metrics {
+            for (op, l) in &m.latency {
+                writeln!(
+                    out,
+                    "ice_async_op_latency_max_microseconds{{app=\"{}\",op=\"{}\"}} {}",
+                    escape_label(name),
+                    escape_label(op),
+                    l.max_micros
+                ).unwrap();
+            }
+        }
+    }
+
+    out
+}
+
+fn write_header(out: &mut String, name: &str, ty: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, ty).unwrap();
+}
+
+fn escape_label(v: &str) -> String {
+    let mut out = String::with_capacity(v.len());
+    for c in v.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '"' => out.push_str("\\\""),
+            '\n' => out.push_str("\\n"),
+            _ => out.push(c)
+        }
+    }
+    out
+}
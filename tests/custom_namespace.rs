@@ -0,0 +1,39 @@
+extern crate ice_core;
+extern crate wasm_core;
+
+use ice_core::config::Config;
+use ice_core::{Server, CustomNamespaceDef};
+use wasm_core::value::Value;
+
+fn server() -> Server {
+    Server::new(Config {
+        applications: Vec::new(),
+        services: Vec::new()
+    })
+}
+
+fn echo(prefix: &str) -> CustomNamespaceDef {
+    CustomNamespaceDef::new(prefix)
+        .function("echo", |ctx| Some(ctx.args[0]))
+}
+
+#[test]
+fn register_namespace() {
+    let server = server();
+    assert!(server.register_namespace(echo("demo")).is_ok());
+    assert!(server.register_namespace(echo("other")).is_ok());
+}
+
+#[test]
+fn register_namespace_rejects_duplicate_prefix() {
+    let server = server();
+    assert!(server.register_namespace(echo("demo")).is_ok());
+    assert!(server.register_namespace(echo("demo")).is_err());
+}
+
+#[test]
+fn register_namespace_rejects_invalid_prefix() {
+    let server = server();
+    assert!(server.register_namespace(echo("")).is_err());
+    assert!(server.register_namespace(echo("my_ns")).is_err());
+}